@@ -0,0 +1,363 @@
+//! Stateful per-key window aggregation.
+//!
+//! Each input adds to the count and value sum of every window it falls in.
+//! Open windows live in an embedded redb database, and a message is only
+//! acknowledged after the transaction holding its contribution commits, so
+//! a restart resumes the open windows where they left off. A background task
+//! publishes a summary for each window once its end time passes.
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use lapin::message::Delivery;
+use redb::{Database, ReadableTable, TableDefinition};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, info, instrument};
+
+use crate::config::{AggregateConfig, WindowConfig};
+use crate::expr::Expression;
+use crate::messaging::consumer::MessageHandler;
+use crate::messaging::{OutputStage, RoutedMessage};
+use crate::money;
+
+/// Open windows keyed by `{end_ms}|{start_ms}|{group}`, so closed windows
+/// are a prefix range scan.
+const WINDOWS: TableDefinition<&str, &[u8]> = TableDefinition::new("windows");
+
+/// Stored as JSON. The sum is a decimal, which serializes as a string, so
+/// repeated additions don't accumulate binary rounding errors.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct WindowState {
+    key: String,
+    start_ms: i64,
+    end_ms: i64,
+    count: u64,
+    sum: Decimal,
+}
+
+#[derive(Clone)]
+pub struct WindowAggregator {
+    db: Arc<Database>,
+    group_by: String,
+    value: Expression,
+    window: WindowConfig,
+}
+
+impl WindowAggregator {
+    pub fn open(config: &AggregateConfig) -> Result<Self> {
+        match config.window {
+            WindowConfig::Tumbling { size_ms } if size_ms <= 0 => {
+                return Err(anyhow!("window size_ms must be positive"))
+            }
+            WindowConfig::Sliding { size_ms, slide_ms } if slide_ms <= 0 || slide_ms > size_ms => {
+                return Err(anyhow!("window slide_ms must be positive and at most size_ms"))
+            }
+            _ => {}
+        }
+
+        if let Some(parent) = std::path::Path::new(&config.state_path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let db = Database::create(&config.state_path)
+            .with_context(|| format!("failed to open window state `{}`", config.state_path))?;
+
+        // Make sure the table exists before the first read
+        let txn = db.begin_write()?;
+        txn.open_table(WINDOWS)?;
+        txn.commit()?;
+
+        info!(
+            group_by = %config.group_by,
+            state_path = %config.state_path,
+            window = ?config.window,
+            "Window aggregator opened"
+        );
+
+        let value = Expression::parse(&config.value)?;
+        value.check_decimal()?;
+
+        Ok(Self {
+            db: Arc::new(db),
+            group_by: config.group_by.clone(),
+            value,
+            window: config.window.clone(),
+        })
+    }
+
+    /// Periodically publish and remove windows whose end time has passed.
+    pub fn spawn_closer(&self, output: OutputStage, interval: Duration) -> JoinHandle<()> {
+        let aggregator = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = aggregator.close_windows(&output).await {
+                    error!(error = %e, "Failed to close windows");
+                }
+            }
+        })
+    }
+
+    async fn close_windows(&self, output: &OutputStage) -> Result<()> {
+        let now_ms = Utc::now().timestamp_millis();
+        let db = self.db.clone();
+
+        let closed = tokio::task::spawn_blocking(move || -> Result<Vec<(String, WindowState)>> {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(WINDOWS)?;
+            let upper = format!("{:020}", now_ms);
+
+            table
+                .range::<&str>(..upper.as_str())?
+                .map(|entry| {
+                    let (key, value) = entry?;
+                    Ok((key.value().to_string(), serde_json::from_slice(value.value())?))
+                })
+                .collect()
+        })
+        .await??;
+
+        for (key, window) in closed {
+            let report = output
+                .publish_all(&[RoutedMessage::new(self.summary(&window)?)], &Value::Null)
+                .await;
+            if report.failed > 0 {
+                // Leave the window in place and retry on the next tick
+                return Err(anyhow!(
+                    "failed to publish window summary: {}",
+                    report.errors.join("; ")
+                ));
+            }
+
+            let db = self.db.clone();
+            tokio::task::spawn_blocking(move || -> Result<()> {
+                let txn = db.begin_write()?;
+                txn.open_table(WINDOWS)?.remove(key.as_str())?;
+                txn.commit()?;
+                Ok(())
+            })
+            .await??;
+
+            info!(
+                key = %window.key,
+                count = window.count,
+                sum = %window.sum,
+                "Window closed"
+            );
+        }
+
+        Ok(())
+    }
+
+    fn summary(&self, window: &WindowState) -> Result<Value> {
+        Ok(json!({
+            "group_by": self.group_by,
+            "key": window.key,
+            "window_start": format_ms(window.start_ms),
+            "window_end": format_ms(window.end_ms),
+            "count": window.count,
+            "sum": money::json_number::serialize(&window.sum, serde_json::value::Serializer)?,
+        }))
+    }
+
+    /// Start and end (exclusive) of every window containing `at_ms`.
+    fn windows_for(&self, at_ms: i64) -> Vec<(i64, i64)> {
+        match self.window {
+            WindowConfig::Tumbling { size_ms } => {
+                let start = at_ms - at_ms.rem_euclid(size_ms);
+                vec![(start, start + size_ms)]
+            }
+            WindowConfig::Sliding { size_ms, slide_ms } => {
+                let mut start = at_ms - at_ms.rem_euclid(slide_ms);
+                let mut windows = Vec::new();
+                while start + size_ms > at_ms {
+                    windows.push((start, start + size_ms));
+                    start -= slide_ms;
+                }
+                windows
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MessageHandler for WindowAggregator {
+    #[instrument(skip(self, delivery))]
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let message: Value = serde_json::from_slice(&delivery.data)?;
+
+        let key = match message.get(&self.group_by) {
+            Some(Value::String(s)) => s.clone(),
+            Some(Value::Null) | None => {
+                return Err(anyhow!("message has no `{}` to group by", self.group_by))
+            }
+            Some(other) => other.to_string(),
+        };
+        let value = self.value.eval_decimal(&message)?;
+
+        let windows = self.windows_for(Utc::now().timestamp_millis());
+        let db = self.db.clone();
+
+        // Commit before returning so the delivery is acked only once its
+        // contribution is durable
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let txn = db.begin_write()?;
+            {
+                let mut table = txn.open_table(WINDOWS)?;
+                for (start_ms, end_ms) in windows {
+                    let id = format!("{:020}|{:020}|{}", end_ms, start_ms, key);
+                    let mut state = match table.get(id.as_str())? {
+                        Some(existing) => serde_json::from_slice(existing.value())?,
+                        None => WindowState {
+                            key: key.clone(),
+                            start_ms,
+                            end_ms,
+                            ..Default::default()
+                        },
+                    };
+                    state.count += 1;
+                    state.sum = state
+                        .sum
+                        .checked_add(value)
+                        .ok_or_else(|| anyhow!("window sum for `{}` overflowed", key))?;
+                    table.insert(id.as_str(), serde_json::to_vec(&state)?.as_slice())?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await??;
+
+        Ok(Vec::new())
+    }
+}
+
+fn format_ms(ms: i64) -> String {
+    DateTime::<Utc>::from_timestamp_millis(ms)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::{acker::Acker, BasicProperties};
+
+    fn aggregator(window: Value) -> (WindowAggregator, std::path::PathBuf) {
+        aggregator_of("amount", window)
+    }
+
+    fn aggregator_of(value: &str, window: Value) -> (WindowAggregator, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("aggregate_{}", uuid::Uuid::new_v4()));
+        let config: AggregateConfig = serde_json::from_value(json!({
+            "group_by": "user_id",
+            "value": value,
+            "window": window,
+            "state_path": dir.join("windows.redb"),
+        }))
+        .unwrap();
+        (WindowAggregator::open(&config).unwrap(), dir)
+    }
+
+    fn delivery(body: Value) -> Delivery {
+        Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "input".into(),
+            redelivered: false,
+            properties: BasicProperties::default(),
+            data: serde_json::to_vec(&body).unwrap(),
+            acker: Acker::default(),
+        }
+    }
+
+    fn stored(aggregator: &WindowAggregator) -> Vec<Value> {
+        let txn = aggregator.db.begin_read().unwrap();
+        let table = txn.open_table(WINDOWS).unwrap();
+        table
+            .iter()
+            .unwrap()
+            .map(|entry| serde_json::from_slice(entry.unwrap().1.value()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sums_exactly_and_stores_sum_as_string() {
+        let (aggregator, dir) = aggregator(json!({"type": "tumbling", "size_ms": 3_600_000}));
+
+        for amount in [0.1, 0.2, 999.99] {
+            let outputs = aggregator
+                .handle(&delivery(json!({"user_id": "u1", "amount": amount})))
+                .await
+                .unwrap();
+            assert!(outputs.is_empty());
+        }
+
+        // A window boundary may fall between the messages
+        let windows = stored(&aggregator);
+        let total: Decimal = windows
+            .iter()
+            .map(|window| window["sum"].as_str().unwrap().parse::<Decimal>().unwrap())
+            .sum();
+        assert_eq!(total, "1000.29".parse::<Decimal>().unwrap());
+
+        // Summaries carry the sum as a plain JSON number
+        let window: WindowState = serde_json::from_value(windows[0].clone()).unwrap();
+        assert!(aggregator.summary(&window).unwrap()["sum"].is_number());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sums_products_exactly() {
+        let (aggregator, dir) =
+            aggregator_of("quantity * price", json!({"type": "tumbling", "size_ms": 3_600_000}));
+
+        for (quantity, price) in [(3, 999.99), (7, 0.1)] {
+            aggregator
+                .handle(&delivery(json!({"user_id": "u1", "quantity": quantity, "price": price})))
+                .await
+                .unwrap();
+        }
+
+        let total: Decimal = stored(&aggregator)
+            .iter()
+            .map(|window| window["sum"].as_str().unwrap().parse::<Decimal>().unwrap())
+            .sum();
+        assert_eq!(total, "3000.67".parse::<Decimal>().unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_values_that_are_not_arithmetic() {
+        let dir = std::env::temp_dir().join(format!("aggregate_{}", uuid::Uuid::new_v4()));
+        let config: AggregateConfig = serde_json::from_value(json!({
+            "group_by": "user_id",
+            "value": "price > 10",
+            "window": {"type": "tumbling", "size_ms": 1000},
+            "state_path": dir.join("windows.redb"),
+        }))
+        .unwrap();
+        assert!(WindowAggregator::open(&config).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn assigns_every_overlapping_sliding_window() {
+        let (aggregator, dir) =
+            aggregator(json!({"type": "sliding", "size_ms": 300, "slide_ms": 100}));
+
+        assert_eq!(aggregator.windows_for(250), vec![(200, 500), (100, 400), (0, 300)]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
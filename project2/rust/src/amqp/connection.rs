@@ -1,12 +1,18 @@
 use anyhow::Result;
-use lapin::{
-    options::*,
-    types::FieldTable,
-    Connection, ConnectionProperties,
-};
+use lapin::{options::*, types::FieldTable, Connection, ConnectionProperties};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 use tracing::{error, info, warn};
 
+use crate::metrics::Metrics;
+
+/// How often to check whether the broker has blocked the connection.
+const BLOCKED_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How often to read the message count of the input and output queues.
+const QUEUE_DEPTH_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 pub struct AMQPConnection {
     connection: Arc<Connection>,
@@ -50,6 +56,28 @@ impl AMQPConnection {
         Ok(channel)
     }
 
+    /// Channel in publisher-confirm mode, so every publish resolves only
+    /// once the broker has taken responsibility for the message.
+    pub async fn create_confirm_channel(&self) -> Result<lapin::Channel> {
+        let channel = self.create_channel().await?;
+
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+
+        Ok(channel)
+    }
+
+    /// Channel in transaction mode, for consuming and publishing on the
+    /// same channel so that publishes and acks commit together.
+    pub async fn create_transactional_channel(&self, prefetch_count: u16) -> Result<lapin::Channel> {
+        let channel = self.create_channel_with_qos(prefetch_count).await?;
+
+        channel.tx_select().await?;
+
+        Ok(channel)
+    }
+
     pub async fn create_channel_with_qos(&self, prefetch_count: u16) -> Result<lapin::Channel> {
         let channel = self.connection.create_channel().await?;
         
@@ -60,7 +88,91 @@ impl AMQPConnection {
         Ok(channel)
     }
 
+    /// Track `connection.blocked`/`connection.unblocked`, which RabbitMQ
+    /// sends while a memory or disk alarm is in effect. lapin records the
+    /// state but offers no callback, so it is polled. The returned receiver
+    /// holds `true` while the connection is blocked.
+    pub fn watch_blocked(&self, metrics: Arc<Metrics>) -> watch::Receiver<bool> {
+        let (tx, rx) = watch::channel(false);
+        let connection = self.connection.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(BLOCKED_POLL_INTERVAL);
+            let mut last_tick = Instant::now();
+            let mut blocked_since: Option<Instant> = None;
+
+            loop {
+                ticker.tick().await;
+                let now = Instant::now();
+                let blocked = connection.status().blocked();
+
+                if blocked {
+                    metrics.add_amqp_blocked_duration(now.duration_since(last_tick));
+                }
+                last_tick = now;
+
+                match (blocked, blocked_since) {
+                    (true, None) => {
+                        warn!("RabbitMQ blocked the connection; pausing consumption");
+                        blocked_since = Some(now);
+                    }
+                    (false, Some(since)) => {
+                        info!(
+                            blocked_ms = since.elapsed().as_millis(),
+                            "RabbitMQ unblocked the connection; resuming consumption"
+                        );
+                        blocked_since = None;
+                    }
+                    _ => continue,
+                }
+
+                metrics.set_amqp_connection_blocked(blocked);
+                if tx.send(blocked).is_err() {
+                    break;
+                }
+            }
+        });
+
+        rx
+    }
+
     pub fn is_connected(&self) -> bool {
         self.connection.status().connected()
     }
+
+    /// Export the ready message count of each queue as
+    /// `rabbitmq_queue_depth`. A passive declare of a queue that doesn't
+    /// exist closes its channel, so each check gets a channel of its own.
+    pub fn watch_queue_depth(&self, queues: Vec<String>, metrics: Arc<Metrics>) {
+        let connection = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(QUEUE_DEPTH_POLL_INTERVAL);
+            while connection.is_connected() {
+                ticker.tick().await;
+                for queue in &queues {
+                    match connection.queue_depth(queue).await {
+                        Ok(depth) => metrics.set_queue_depth(queue, depth as f64),
+                        Err(e) => warn!(queue = %queue, error = %e, "Failed to read queue depth"),
+                    }
+                }
+            }
+        });
+    }
+
+    async fn queue_depth(&self, queue: &str) -> Result<u32> {
+        let channel = self.create_channel().await?;
+        let declared = channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    passive: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        let _ = channel.close(200, "OK").await;
+        Ok(declared.message_count())
+    }
 }
\ No newline at end of file
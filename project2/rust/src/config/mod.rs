@@ -7,12 +7,22 @@ pub struct AppConfig {
     pub amqp: Amqp,
     pub queues: Queues,
     pub logging: Logging,
+    #[serde(default)]
+    pub pipeline: Pipeline,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct App {
     pub name: String,
     pub port: u16,
+    /// Address of the admin endpoints, which change settings at runtime.
+    /// Kept off `port` so they aren't exposed wherever metrics are scraped.
+    #[serde(default = "default_admin_addr")]
+    pub admin_addr: String,
+}
+
+fn default_admin_addr() -> String {
+    "127.0.0.1:8084".to_string()
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -20,6 +30,49 @@ pub struct Amqp {
     pub url: String,
     pub concurrent: usize,
     pub prefetch_count: u16,
+    #[serde(default)]
+    pub consumer: ConsumerConfig,
+}
+
+/// Options for the subscription to the input queue.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ConsumerConfig {
+    /// Instance name used in consumer tags. Defaults to `POD_NAME`, then
+    /// `HOSTNAME`, then the system host name.
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Fail if another consumer is on the queue, and keep others off it.
+    #[serde(default)]
+    pub exclusive: bool,
+    /// `x-priority`: higher-priority consumers get messages first while
+    /// they have prefetch capacity.
+    #[serde(default)]
+    pub priority: Option<i32>,
+    /// Declare the input queue with `x-single-active-consumer`, so only one
+    /// consumer receives messages and the others stand by.
+    #[serde(default)]
+    pub single_active_consumer: bool,
+    /// Ask the broker not to deliver messages published on this connection.
+    #[serde(default)]
+    pub no_local: bool,
+    /// Declare the input queue again when resubscribing after the broker
+    /// cancels the consumer. When off, a deleted input queue stops the
+    /// processor instead of being recreated.
+    #[serde(default = "default_true")]
+    pub redeclare_on_cancel: bool,
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        Self {
+            instance: None,
+            exclusive: false,
+            priority: None,
+            single_active_consumer: false,
+            no_local: false,
+            redeclare_on_cancel: true,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -34,6 +87,697 @@ pub struct Logging {
     pub format: String,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Pipeline {
+    #[serde(default = "default_pipeline_name")]
+    pub name: String,
+    #[serde(default)]
+    pub transforms: Vec<TransformConfig>,
+    #[serde(default)]
+    pub script: Option<ScriptConfig>,
+    /// When set, messages are handled by this wasm plugin instead of the
+    /// transform/script path.
+    #[serde(default)]
+    pub wasm: Option<WasmConfig>,
+    /// Rounding and checks for `price` on the typed forwarding path.
+    #[serde(default)]
+    pub money: Option<MoneyConfig>,
+    /// What the typed forwarding path does with fields it doesn't know.
+    #[serde(default)]
+    pub unknown_fields: UnknownFields,
+    #[serde(default)]
+    pub routing: Routing,
+    #[serde(default)]
+    pub filters: Vec<FilterConfig>,
+    #[serde(default)]
+    pub on_partial_failure: PartialFailurePolicy,
+    /// When the input is acknowledged relative to publishing its outputs.
+    #[serde(default)]
+    pub delivery_guarantee: DeliveryGuarantee,
+    #[serde(default)]
+    pub ack_mode: AckMode,
+    /// When `ack_mode` is `batched`, how many acks to hold back at most.
+    #[serde(default)]
+    pub ack_batch: AckBatchConfig,
+    /// Handler middleware, outermost first.
+    #[serde(default = "default_layers")]
+    pub layers: Vec<LayerConfig>,
+    /// Deadline for handling and publishing one message. Late messages are
+    /// settled according to `on_partial_failure`.
+    #[serde(default)]
+    pub message_timeout_ms: Option<u64>,
+    /// Publish outputs in batches instead of one message per output.
+    #[serde(default)]
+    pub batch: Option<BatchConfig>,
+    /// Process messages with the same key sequentially, in arrival order.
+    #[serde(default)]
+    pub ordering: Option<OrderingConfig>,
+    /// Let the consumer tune its own concurrency between bounds instead of
+    /// always running `amqp.concurrent` messages at once.
+    #[serde(default)]
+    pub adaptive_concurrency: Option<AdaptiveConcurrencyConfig>,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    /// Pause consumption after repeated publish failures.
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// When set, messages feed per-key window aggregates instead of being
+    /// forwarded; summaries are published as windows close.
+    #[serde(default)]
+    pub aggregate: Option<AggregateConfig>,
+}
+
+fn default_pipeline_name() -> String {
+    "default".to_string()
+}
+
+fn default_layers() -> Vec<LayerConfig> {
+    vec![LayerConfig::Logging, LayerConfig::Metrics]
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self {
+            name: default_pipeline_name(),
+            transforms: Vec::new(),
+            script: None,
+            wasm: None,
+            money: None,
+            unknown_fields: UnknownFields::default(),
+            routing: Routing::default(),
+            filters: Vec::new(),
+            on_partial_failure: PartialFailurePolicy::default(),
+            delivery_guarantee: DeliveryGuarantee::default(),
+            ack_mode: AckMode::default(),
+            ack_batch: AckBatchConfig::default(),
+            layers: default_layers(),
+            message_timeout_ms: None,
+            batch: None,
+            ordering: None,
+            adaptive_concurrency: None,
+            rate_limits: RateLimits::default(),
+            circuit_breaker: None,
+            aggregate: None,
+        }
+    }
+}
+
+/// A single declarative transform step, applied in list order to the decoded
+/// message body.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransformConfig {
+    Rename { from: String, to: String },
+    Drop { field: String },
+    Set { field: String, value: serde_json::Value },
+    Compute { field: String, expr: String },
+    Timestamp { field: String },
+    /// Emit one message per element of an array field. Object elements are
+    /// merged into the top level unless `into` names a field to hold them.
+    Split {
+        field: String,
+        #[serde(default)]
+        into: Option<String>,
+    },
+    Uuid {
+        field: String,
+        #[serde(default)]
+        version: UuidVersion,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UuidVersion {
+    #[default]
+    V4,
+    V7,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ScriptConfig {
+    pub path: String,
+    /// Exchange used for outputs that set a `routing_key`; empty means the
+    /// default exchange, where the routing key is a queue name.
+    #[serde(default)]
+    pub exchange: String,
+    #[serde(default = "default_script_max_operations")]
+    pub max_operations: u64,
+    #[serde(default = "default_script_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_script_reload_interval_ms")]
+    pub reload_interval_ms: u64,
+}
+
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+fn default_script_timeout_ms() -> u64 {
+    50
+}
+
+fn default_script_reload_interval_ms() -> u64 {
+    2_000
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WasmConfig {
+    pub path: String,
+    #[serde(default)]
+    pub exchange: String,
+    #[serde(default = "default_wasm_fuel")]
+    pub fuel: u64,
+    #[serde(default = "default_wasm_max_memory_bytes")]
+    pub max_memory_bytes: usize,
+}
+
+fn default_wasm_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_wasm_max_memory_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Routing {
+    /// Destination when no rule matches; defaults to `queues.output_queue`.
+    #[serde(default)]
+    pub default: Option<RouteTarget>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RouteConfig {
+    pub name: String,
+    pub when: String,
+    #[serde(default)]
+    pub exchange: String,
+    pub routing_key: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RouteTarget {
+    #[serde(default)]
+    pub exchange: String,
+    pub routing_key: String,
+}
+
+/// Messages matching `when` are acknowledged and dropped instead of being
+/// published.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FilterConfig {
+    pub name: String,
+    pub when: String,
+}
+
+/// How to settle a delivery when some of its outputs could not be
+/// published. Outputs that did publish are not retracted.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PartialFailurePolicy {
+    /// Nack and requeue; outputs that already published are duplicated on
+    /// redelivery.
+    #[default]
+    Requeue,
+    /// Nack without requeue, dead-lettering the input if the queue has a DLX.
+    Reject,
+    /// Ack anyway, accepting that the failed outputs are lost.
+    Ack,
+}
+
+/// Ordering of publish and acknowledgement for each delivery.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryGuarantee {
+    /// Ack on receipt, then handle and publish. A crash or failure loses the
+    /// message, but its outputs are never duplicated.
+    AtMostOnce,
+    /// Publish with confirms, then ack. A crash between the two redelivers
+    /// the input and duplicates its outputs, but nothing is lost.
+    #[default]
+    AtLeastOnce,
+    /// Publish and ack inside an AMQP transaction on the consumer channel,
+    /// so a crash before the commit discards both.
+    Transactional,
+}
+
+/// How deliveries are acknowledged to the broker.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AckMode {
+    /// The broker considers a message acknowledged as soon as it is sent
+    /// (`no_ack`). Only valid with the at-most-once guarantee.
+    Auto,
+    /// One ack per delivery.
+    #[default]
+    Manual,
+    /// Acks are held back and sent as one `multiple` ack covering every
+    /// delivery up to the highest contiguous delivery tag. Only valid with
+    /// the at-least-once guarantee.
+    Batched,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AckBatchConfig {
+    /// Send the held-back acks once this many are waiting.
+    #[serde(default = "default_ack_batch_max_messages")]
+    pub max_messages: usize,
+    /// Send the held-back acks at least this often.
+    #[serde(default = "default_ack_batch_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+fn default_ack_batch_max_messages() -> usize {
+    100
+}
+
+fn default_ack_batch_max_delay_ms() -> u64 {
+    50
+}
+
+impl Default for AckBatchConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: default_ack_batch_max_messages(),
+            max_delay_ms: default_ack_batch_max_delay_ms(),
+        }
+    }
+}
+
+/// A middleware layer wrapped around the message handler.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LayerConfig {
+    /// Per-message tracing span with a request id.
+    Logging,
+    /// Received/failed counters and processing duration.
+    Metrics,
+    /// Fail the wrapped handler after `timeout_ms`.
+    Timeout { timeout_ms: u64 },
+    /// Retry the wrapped handler on error with exponential backoff.
+    Retry {
+        #[serde(default = "default_retry_max_attempts")]
+        max_attempts: u32,
+        #[serde(default = "default_retry_backoff_ms")]
+        backoff_ms: u64,
+    },
+    /// Drop messages whose `message_id` was already handled recently.
+    Dedup {
+        /// Most message ids remembered; the oldest are forgotten first.
+        #[serde(default = "default_dedup_capacity")]
+        capacity: usize,
+        /// How long a message id is remembered.
+        #[serde(default = "default_dedup_ttl_ms")]
+        ttl_ms: u64,
+    },
+    /// Check bodies against a versioned JSON Schema; invalid messages go to
+    /// the rejection queue instead of the wrapped handler.
+    Validation(ValidationConfig),
+    /// Stamp outputs with processing metadata.
+    Enrich(EnrichConfig),
+    /// Unwrap CloudEvents input and wrap outputs as CloudEvents.
+    Cloudevents(CloudEventsConfig),
+    /// Join outputs against a local reference table.
+    Lookup(LookupConfig),
+}
+
+/// Reference data joined onto outputs, e.g. a product catalog keyed by
+/// `product_name` adding `sku` and `category`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LookupConfig {
+    /// Names the table in metrics. Defaults to the file stem.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// CSV file with a header row, or JSON array of objects or object of
+    /// objects keyed by the key column.
+    pub path: String,
+    /// Defaults to the file extension.
+    #[serde(default)]
+    pub format: Option<LookupFormat>,
+    /// Output body field to join on, as a dotted path.
+    pub key: String,
+    /// Column holding the key in the reference data. Defaults to `key`.
+    #[serde(default)]
+    pub key_column: Option<String>,
+    /// Columns copied onto the output. Defaults to every column except the
+    /// key column.
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// Prepended to every copied field name.
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub on_miss: LookupMissPolicy,
+    /// Queue that unmatched outputs go to when `on_miss` is `dead_letter`.
+    #[serde(default)]
+    pub dead_letter_queue: Option<String>,
+    #[serde(default = "default_lookup_reload_interval_ms")]
+    pub reload_interval_ms: u64,
+}
+
+fn default_lookup_reload_interval_ms() -> u64 {
+    5_000
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LookupFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LookupMissPolicy {
+    /// Publish the output without the reference fields.
+    #[default]
+    Pass,
+    /// Don't publish the output.
+    Drop,
+    /// Publish the output to `dead_letter_queue` instead.
+    DeadLetter,
+}
+
+/// CloudEvents 1.0 over AMQP. Attribute values are templates rendered
+/// against each output body and the input headers, e.g. `"{user_id}"` or
+/// `"{headers.cloudEvents:id}"`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CloudEventsConfig {
+    #[serde(default)]
+    pub mode: CloudEventsMode,
+    pub source: String,
+    /// The CloudEvents `type` attribute.
+    pub event_type: String,
+    /// Defaults to a new UUID per output.
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub subject: Option<String>,
+    /// Defaults to the time the output was produced.
+    #[serde(default)]
+    pub time: Option<String>,
+    /// Unwrap structured-mode input so the handler sees only `data`.
+    #[serde(default = "default_true")]
+    pub unwrap_input: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloudEventsMode {
+    /// The whole event, data included, as an `application/cloudevents+json`
+    /// body.
+    #[default]
+    Structured,
+    /// Attributes as `cloudEvents:`-prefixed headers, data as the body.
+    Binary,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EnrichConfig {
+    #[serde(default)]
+    pub target: EnrichTarget,
+    #[serde(default = "default_enrich_fields")]
+    pub fields: Vec<EnrichField>,
+    /// Prepended to every field or header name, e.g. `_` or `x-`.
+    #[serde(default)]
+    pub prefix: String,
+    /// Value for `processor_instance`. Defaults to `POD_NAME`, then
+    /// `HOSTNAME`, then the system host name.
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnrichTarget {
+    /// Add top-level fields to object bodies.
+    #[default]
+    Body,
+    /// Add AMQP headers.
+    Headers,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EnrichField {
+    ProcessedAt,
+    SourceQueue,
+    ProcessorInstance,
+    ProcessorVersion,
+    Redelivered,
+    LatencyMs,
+}
+
+fn default_enrich_fields() -> Vec<EnrichField> {
+    vec![
+        EnrichField::ProcessedAt,
+        EnrichField::SourceQueue,
+        EnrichField::ProcessorInstance,
+        EnrichField::ProcessorVersion,
+        EnrichField::Redelivered,
+        EnrichField::LatencyMs,
+    ]
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ValidationConfig {
+    /// Directory of `<version>.json` schema files.
+    pub schema_dir: String,
+    /// Header naming the schema version of a message.
+    #[serde(default = "default_schema_version_header")]
+    pub version_header: String,
+    /// Version assumed when the header is missing. Without it, messages
+    /// lacking the header are rejected.
+    #[serde(default)]
+    pub default_version: Option<String>,
+    /// Queue receiving invalid messages together with their errors.
+    pub rejection_queue: String,
+}
+
+fn default_schema_version_header() -> String {
+    "schema_version".to_string()
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    100
+}
+
+fn default_dedup_capacity() -> usize {
+    100_000
+}
+
+fn default_dedup_ttl_ms() -> u64 {
+    600_000
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownFields {
+    /// Forward only the known fields.
+    #[default]
+    Drop,
+    /// Re-emit unknown fields alongside the known ones.
+    PassThrough,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MoneyConfig {
+    /// Decimal places kept.
+    #[serde(default = "default_money_scale")]
+    pub scale: u32,
+    #[serde(default)]
+    pub rounding: RoundingMode,
+    /// ISO 4217 code added to outputs whose input has no `currency`.
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// Reject amounts with more decimal places than `scale` instead of
+    /// rounding them.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+fn default_money_scale() -> u32 {
+    2
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingMode {
+    /// Banker's rounding: ties go to the even digit.
+    #[default]
+    HalfEven,
+    /// Ties away from zero.
+    HalfUp,
+    /// Ties toward zero.
+    HalfDown,
+    /// Toward zero.
+    Down,
+    /// Away from zero.
+    Up,
+    Floor,
+    Ceiling,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BatchConfig {
+    /// Flush once this many input messages are collected. Should not exceed
+    /// `amqp.prefetch_count`, or batches only ever flush on the timer.
+    #[serde(default = "default_batch_max_messages")]
+    pub max_messages: usize,
+    /// Flush this long after the first message of a batch arrives.
+    #[serde(default = "default_batch_max_wait_ms")]
+    pub max_wait_ms: u64,
+    #[serde(default)]
+    pub format: BatchFormat,
+}
+
+fn default_batch_max_messages() -> usize {
+    50
+}
+
+fn default_batch_max_wait_ms() -> u64 {
+    1_000
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchFormat {
+    #[default]
+    JsonArray,
+    Ndjson,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OrderingConfig {
+    /// Body field whose value selects the worker, e.g. `user_id`. Messages
+    /// without the field all go to the first worker.
+    pub key: String,
+    /// Number of sequential workers. Defaults to `amqp.concurrent`.
+    #[serde(default)]
+    pub workers: Option<usize>,
+    /// Messages buffered per worker before consumption waits.
+    #[serde(default = "default_ordering_queue_capacity")]
+    pub queue_capacity: usize,
+}
+
+fn default_ordering_queue_capacity() -> usize {
+    100
+}
+
+/// AIMD limits: grow by one after a full window of healthy publishes,
+/// shrink by `backoff_ratio` when a publish fails or its confirm is slow.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AdaptiveConcurrencyConfig {
+    #[serde(default = "default_adaptive_min_limit")]
+    pub min_limit: usize,
+    #[serde(default = "default_adaptive_max_limit")]
+    pub max_limit: usize,
+    /// Starting limit. Defaults to `amqp.concurrent`.
+    #[serde(default)]
+    pub initial_limit: Option<usize>,
+    /// Publish-confirm latency above which the limit is reduced.
+    #[serde(default = "default_adaptive_latency_threshold_ms")]
+    pub latency_threshold_ms: u64,
+    #[serde(default = "default_adaptive_backoff_ratio")]
+    pub backoff_ratio: f64,
+}
+
+fn default_adaptive_min_limit() -> usize {
+    1
+}
+
+fn default_adaptive_max_limit() -> usize {
+    256
+}
+
+fn default_adaptive_latency_threshold_ms() -> u64 {
+    250
+}
+
+fn default_adaptive_backoff_ratio() -> f64 {
+    0.9
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RateLimits {
+    /// Applied to deliveries before they are handed to the handler.
+    #[serde(default)]
+    pub consume: RateLimitConfig,
+    /// Applied to every message published, including batches and window
+    /// summaries.
+    #[serde(default)]
+    pub publish: RateLimitConfig,
+}
+
+/// Token-bucket limits; an absent rate is unlimited. Each bucket holds up to
+/// one second's worth of tokens, which bounds bursts.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub messages_per_sec: Option<f64>,
+    #[serde(default)]
+    pub bytes_per_sec: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive publish failures that open the breaker.
+    #[serde(default = "default_breaker_failure_threshold")]
+    pub failure_threshold: u32,
+    /// How long to stay open before letting a probe message through.
+    #[serde(default = "default_breaker_open_duration_ms")]
+    pub open_duration_ms: u64,
+}
+
+fn default_breaker_failure_threshold() -> u32 {
+    5
+}
+
+fn default_breaker_open_duration_ms() -> u64 {
+    10_000
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AggregateConfig {
+    /// Field whose value identifies the aggregation key, e.g. `user_id`.
+    pub group_by: String,
+    /// Expression summed per window, e.g. `quantity * price`.
+    pub value: String,
+    pub window: WindowConfig,
+    #[serde(default = "default_aggregate_state_path")]
+    pub state_path: String,
+    /// How often to check for closed windows.
+    #[serde(default = "default_aggregate_close_interval_ms")]
+    pub close_interval_ms: u64,
+}
+
+fn default_aggregate_state_path() -> String {
+    "data/windows.redb".to_string()
+}
+
+fn default_aggregate_close_interval_ms() -> u64 {
+    1_000
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WindowConfig {
+    Tumbling { size_ms: i64 },
+    /// Overlapping windows of `size_ms`, a new one starting every `slide_ms`.
+    Sliding { size_ms: i64, slide_ms: i64 },
+}
+
 impl AppConfig {
     pub fn load() -> Result<Self, ConfigError> {
         let settings = Config::builder()
@@ -0,0 +1,263 @@
+use anyhow::{anyhow, Context, Result};
+use evalexpr::{
+    ContextWithMutableFunctions, ContextWithMutableVariables, EvalexprError, Function,
+    HashMapContext, Node, Operator, Value as ExprValue,
+};
+use rust_decimal::Decimal;
+use serde_json::{Map, Number, Value};
+use std::sync::Arc;
+
+/// An expression parsed once from config and evaluated against each message.
+///
+/// Top-level message fields are exposed as variables; nested objects are
+/// flattened with dotted names, e.g. `customer.tier`. AMQP headers, when
+/// supplied, are exposed the same way under `headers.`, and
+/// `has_header("name")` tests for a header's presence.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    source: String,
+    tree: Node,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self> {
+        let tree = evalexpr::build_operator_tree(source)
+            .with_context(|| format!("invalid expression `{}`", source))?;
+
+        Ok(Self {
+            source: source.to_string(),
+            tree,
+        })
+    }
+
+    pub fn eval(&self, message: &Value) -> Result<Value> {
+        self.eval_with_headers(message, &Value::Null)
+    }
+
+    pub fn eval_with_headers(&self, message: &Value, headers: &Value) -> Result<Value> {
+        let context = build_context(message, headers)?;
+        let result = self
+            .tree
+            .eval_with_context(&context)
+            .with_context(|| format!("failed to evaluate `{}`", self.source))?;
+
+        to_json(result)
+    }
+
+    /// Evaluate as a predicate. A reference to a field or header the message
+    /// doesn't have counts as no match; anything other than a boolean result
+    /// is an error rather than being coerced.
+    pub fn matches(&self, message: &Value, headers: &Value) -> Result<bool> {
+        let context = build_context(message, headers)?;
+
+        match self.tree.eval_with_context(&context) {
+            Ok(ExprValue::Boolean(matched)) => Ok(matched),
+            Ok(other) => Err(anyhow!(
+                "expression `{}` returned {} instead of a boolean",
+                self.source,
+                other
+            )),
+            Err(EvalexprError::VariableIdentifierNotFound(_)) => Ok(false),
+            Err(e) => Err(anyhow!("failed to evaluate `{}`: {}", self.source, e)),
+        }
+    }
+}
+
+impl Expression {
+    /// Fail unless `eval_decimal` can evaluate this expression: numbers,
+    /// fields, `+`, `-`, `*`, `/` and parentheses only.
+    pub fn check_decimal(&self) -> Result<()> {
+        check_decimal(&self.tree)
+            .with_context(|| format!("`{}` is not decimal arithmetic", self.source))
+    }
+
+    /// Evaluate with exact decimal arithmetic instead of floating point, so
+    /// `quantity * price` carries no binary rounding error.
+    pub fn eval_decimal(&self, message: &Value) -> Result<Decimal> {
+        eval_decimal(&self.tree, message)
+            .with_context(|| format!("failed to evaluate `{}`", self.source))
+    }
+}
+
+fn check_decimal(node: &Node) -> Result<()> {
+    match node.operator() {
+        Operator::RootNode
+        | Operator::Add
+        | Operator::Sub
+        | Operator::Neg
+        | Operator::Mul
+        | Operator::Div
+        | Operator::VariableIdentifierRead { .. }
+        | Operator::Const {
+            value: ExprValue::Int(_) | ExprValue::Float(_),
+        } => node.children().iter().try_for_each(check_decimal),
+        other => Err(anyhow!("unsupported operator {:?}", other)),
+    }
+}
+
+fn eval_decimal(node: &Node, message: &Value) -> Result<Decimal> {
+    let operand = |index: usize| -> Result<Decimal> {
+        let child = node
+            .children()
+            .get(index)
+            .ok_or_else(|| anyhow!("missing operand"))?;
+        eval_decimal(child, message)
+    };
+    let overflow = || anyhow!("decimal overflow");
+
+    match node.operator() {
+        Operator::RootNode => operand(0),
+        Operator::Add => operand(0)?.checked_add(operand(1)?).ok_or_else(overflow),
+        Operator::Sub => operand(0)?.checked_sub(operand(1)?).ok_or_else(overflow),
+        Operator::Mul => operand(0)?.checked_mul(operand(1)?).ok_or_else(overflow),
+        Operator::Div => operand(0)?
+            .checked_div(operand(1)?)
+            .ok_or_else(|| anyhow!("division by zero or overflow")),
+        Operator::Neg => Ok(-operand(0)?),
+        Operator::Const {
+            value: ExprValue::Int(i),
+        } => Ok(Decimal::from(*i)),
+        Operator::Const {
+            value: ExprValue::Float(f),
+        } => Decimal::from_str_exact(&f.to_string())
+            .map_err(|e| anyhow!("{} cannot be represented as a decimal: {}", f, e)),
+        Operator::VariableIdentifierRead { identifier } => {
+            match lookup(message, identifier) {
+                Some(Value::Number(number)) => crate::money::from_json_number(number),
+                Some(_) => Err(anyhow!("`{}` is not a number", identifier)),
+                None => Err(anyhow!("message has no `{}`", identifier)),
+            }
+        }
+        other => Err(anyhow!("unsupported operator {:?}", other)),
+    }
+}
+
+/// Field by the dotted name `bind_fields` would give it.
+fn lookup<'a>(message: &'a Value, name: &str) -> Option<&'a Value> {
+    let fields = message.as_object()?;
+    if let Some(value) = fields.get(name) {
+        return Some(value);
+    }
+    let (head, rest) = name.split_once('.')?;
+    lookup(fields.get(head)?, rest)
+}
+
+fn build_context(message: &Value, headers: &Value) -> Result<HashMapContext> {
+    let mut context = HashMapContext::new();
+
+    if let Value::Object(fields) = message {
+        bind_fields(&mut context, "", fields)?;
+    }
+    let header_names: Arc<Vec<String>> = Arc::new(match headers {
+        Value::Object(fields) => {
+            bind_fields(&mut context, "headers.", fields)?;
+            fields.keys().cloned().collect()
+        }
+        _ => Vec::new(),
+    });
+
+    context.set_function(
+        "has_header".to_string(),
+        Function::new(move |name| {
+            let name = name.as_string()?;
+            Ok(ExprValue::Boolean(header_names.contains(&name)))
+        }),
+    )?;
+
+    Ok(context)
+}
+
+fn bind_fields(context: &mut HashMapContext, prefix: &str, fields: &Map<String, Value>) -> Result<()> {
+    for (key, value) in fields {
+        let name = format!("{}{}", prefix, key);
+
+        if let Value::Object(nested) = value {
+            bind_fields(context, &format!("{}.", name), nested)?;
+        } else {
+            context.set_value(name, to_expr(value))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn to_expr(value: &Value) -> ExprValue {
+    match value {
+        Value::Null => ExprValue::Empty,
+        Value::Bool(b) => ExprValue::Boolean(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => ExprValue::Int(i),
+            None => ExprValue::Float(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(s) => ExprValue::String(s.clone()),
+        Value::Array(items) => ExprValue::Tuple(items.iter().map(to_expr).collect()),
+        Value::Object(_) => ExprValue::String(value.to_string()),
+    }
+}
+
+fn to_json(value: ExprValue) -> Result<Value> {
+    Ok(match value {
+        ExprValue::Empty => Value::Null,
+        ExprValue::Boolean(b) => Value::Bool(b),
+        ExprValue::Int(i) => Value::from(i),
+        ExprValue::Float(f) => Number::from_f64(f)
+            .map(Value::Number)
+            .ok_or_else(|| anyhow!("expression produced a non-finite number"))?,
+        ExprValue::String(s) => Value::String(s),
+        ExprValue::Tuple(items) => Value::Array(
+            items
+                .into_iter()
+                .map(to_json)
+                .collect::<Result<Vec<_>>>()?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn evaluates_arithmetic_over_fields() {
+        let expr = Expression::parse("price * quantity").unwrap();
+        let message = json!({"price": 2.5, "quantity": 4});
+
+        assert_eq!(expr.eval(&message).unwrap(), json!(10.0));
+    }
+
+    #[test]
+    fn exposes_nested_fields_with_dotted_names() {
+        let expr = Expression::parse(r#"customer.tier == "gold""#).unwrap();
+        let message = json!({"customer": {"tier": "gold"}});
+
+        assert_eq!(expr.eval(&message).unwrap(), json!(true));
+    }
+
+    #[test]
+    fn evaluates_decimal_arithmetic_exactly() {
+        let expr = Expression::parse("order.quantity * price - 0.1").unwrap();
+        expr.check_decimal().unwrap();
+        let message = json!({"order": {"quantity": 3}, "price": 999.99});
+
+        assert_eq!(
+            expr.eval_decimal(&message).unwrap(),
+            "2999.87".parse::<Decimal>().unwrap()
+        );
+        assert!(expr.eval_decimal(&json!({"price": 1})).is_err());
+        assert!(Expression::parse("price > 1").unwrap().check_decimal().is_err());
+        assert!(Expression::parse("1 / 0").unwrap().eval_decimal(&json!({})).is_err());
+    }
+
+    #[test]
+    fn reports_missing_fields_as_errors() {
+        let expr = Expression::parse("missing + 1").unwrap();
+
+        assert!(expr.eval(&json!({})).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_syntax() {
+        assert!(Expression::parse("(price + 1").is_err());
+    }
+}
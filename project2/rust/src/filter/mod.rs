@@ -0,0 +1,90 @@
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::config::FilterConfig;
+use crate::expr::Expression;
+
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    when: Expression,
+}
+
+/// Drop rules built from `pipeline.filters`. A message matching any rule is
+/// acknowledged without being published.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    rules: Vec<Rule>,
+}
+
+impl MessageFilter {
+    pub fn from_config(filters: &[FilterConfig]) -> Result<Self> {
+        let rules = filters
+            .iter()
+            .map(|filter| {
+                Ok(Rule {
+                    name: filter.name.clone(),
+                    when: Expression::parse(&filter.when)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Name of the first rule that drops this message, if any.
+    pub fn matching_rule(&self, message: &Value, headers: &Value) -> Result<Option<&str>> {
+        for rule in &self.rules {
+            if rule.when.matches(message, headers)? {
+                return Ok(Some(&rule.name));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter() -> MessageFilter {
+        let filters: Vec<FilterConfig> = serde_json::from_value(json!([
+            {"name": "test_users", "when": "str::regex_matches(user_id, \"^test-\")"},
+            {"name": "replayed", "when": "has_header(\"x-replay\")"},
+            {"name": "empty", "when": "quantity == 0"}
+        ]))
+        .unwrap();
+        MessageFilter::from_config(&filters).unwrap()
+    }
+
+    #[test]
+    fn reports_first_matching_rule() {
+        let filter = filter();
+
+        let message = json!({"user_id": "test-1", "quantity": 0});
+        assert_eq!(filter.matching_rule(&message, &json!({})).unwrap(), Some("test_users"));
+
+        let message = json!({"user_id": "u1", "quantity": 0});
+        assert_eq!(
+            filter.matching_rule(&message, &json!({"x-replay": true})).unwrap(),
+            Some("replayed")
+        );
+        assert_eq!(filter.matching_rule(&message, &json!({})).unwrap(), Some("empty"));
+    }
+
+    #[test]
+    fn missing_fields_do_not_match() {
+        assert_eq!(filter().matching_rule(&json!({}), &json!({})).unwrap(), None);
+    }
+
+    #[test]
+    fn non_boolean_rules_are_errors() {
+        let filters: Vec<FilterConfig> =
+            serde_json::from_value(json!([{"name": "total", "when": "price * 2"}])).unwrap();
+        let filter = MessageFilter::from_config(&filters).unwrap();
+
+        assert!(filter.matching_rule(&json!({"price": 1}), &json!({})).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::config::{LookupConfig, LookupFormat};
+use crate::watcher;
+
+type Rows = HashMap<String, Map<String, Value>>;
+
+/// Reference data loaded from a CSV or JSON file into memory, keyed by one
+/// column. The rows are swapped in place when the file changes on disk.
+pub struct LookupTable {
+    name: String,
+    path: PathBuf,
+    format: LookupFormat,
+    key_column: String,
+    rows: RwLock<Arc<Rows>>,
+}
+
+impl LookupTable {
+    pub fn load(config: &LookupConfig) -> Result<Arc<Self>> {
+        let path = PathBuf::from(&config.path);
+        let format = match config.format {
+            Some(format) => format,
+            None => match path.extension().and_then(|ext| ext.to_str()) {
+                Some("csv") => LookupFormat::Csv,
+                Some("json") => LookupFormat::Json,
+                _ => {
+                    return Err(anyhow!(
+                        "can't tell the format of lookup table {}, set `format`",
+                        path.display()
+                    ))
+                }
+            },
+        };
+        let name = config.name.clone().unwrap_or_else(|| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| config.path.clone())
+        });
+        let key_column = config.key_column.clone().unwrap_or_else(|| config.key.clone());
+
+        let rows = read_rows(&path, format, &key_column)?;
+        info!(table = %name, path = %config.path, rows = rows.len(), "Lookup table loaded");
+
+        let table = Arc::new(Self {
+            name,
+            path: path.clone(),
+            format,
+            key_column,
+            rows: RwLock::new(Arc::new(rows)),
+        });
+
+        let watched = Arc::downgrade(&table);
+        watcher::watch_file(
+            path,
+            Duration::from_millis(config.reload_interval_ms),
+            move || match watched.upgrade() {
+                Some(table) => {
+                    table.reload();
+                    ControlFlow::Continue(())
+                }
+                None => ControlFlow::Break(()),
+            },
+        );
+
+        Ok(table)
+    }
+
+    /// Re-read the file, keeping the previous rows if it fails.
+    pub fn reload(&self) {
+        match read_rows(&self.path, self.format, &self.key_column) {
+            Ok(rows) => {
+                info!(table = %self.name, rows = rows.len(), "Lookup table reloaded");
+                *self.rows.write().unwrap() = Arc::new(rows);
+            }
+            Err(e) => {
+                error!(
+                    table = %self.name,
+                    path = %self.path.display(),
+                    error = %e,
+                    "Failed to reload lookup table, keeping previous version"
+                );
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn key_column(&self) -> &str {
+        &self.key_column
+    }
+
+    /// Current rows. Callers hold on to a snapshot, so a reload never
+    /// changes the data halfway through a message.
+    pub fn rows(&self) -> Arc<Rows> {
+        self.rows.read().unwrap().clone()
+    }
+}
+
+/// Text form of a key, so that `42` in a message matches `"42"` in a CSV
+/// file.
+pub fn key_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn read_rows(path: &Path, format: LookupFormat, key_column: &str) -> Result<Rows> {
+    let rows = match format {
+        LookupFormat::Csv => read_csv(path)?,
+        LookupFormat::Json => read_json(path, key_column)?,
+    };
+
+    rows.into_iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let key = row.get(key_column).and_then(key_string).ok_or_else(|| {
+                anyhow!(
+                    "row {} of {} has no `{}` value",
+                    i + 1,
+                    path.display(),
+                    key_column
+                )
+            })?;
+            Ok((key, row))
+        })
+        .collect()
+}
+
+fn read_csv(path: &Path) -> Result<Vec<Map<String, Value>>> {
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("failed to open lookup table {}", path.display()))?;
+    let columns = reader.headers()?.clone();
+
+    reader
+        .records()
+        .map(|record| {
+            let record = record.with_context(|| format!("invalid CSV in {}", path.display()))?;
+            Ok(columns
+                .iter()
+                .zip(record.iter())
+                .map(|(column, value)| (column.to_string(), Value::String(value.to_string())))
+                .collect())
+        })
+        .collect()
+}
+
+/// Either an array of row objects or an object of row objects. In the
+/// latter the object keys are the lookup keys, added to each row as the key
+/// column when missing.
+fn read_json(path: &Path, key_column: &str) -> Result<Vec<Map<String, Value>>> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read lookup table {}", path.display()))?;
+    let data: Value = serde_json::from_str(&source)
+        .with_context(|| format!("lookup table {} is not valid JSON", path.display()))?;
+
+    let not_object = || anyhow!("lookup table {} has a row that isn't an object", path.display());
+    match data {
+        Value::Array(rows) => rows
+            .into_iter()
+            .map(|row| match row {
+                Value::Object(row) => Ok(row),
+                _ => Err(not_object()),
+            })
+            .collect(),
+        Value::Object(rows) => rows
+            .into_iter()
+            .map(|(key, row)| match row {
+                Value::Object(mut row) => {
+                    row.entry(key_column).or_insert(Value::String(key));
+                    Ok(row)
+                }
+                _ => Err(not_object()),
+            })
+            .collect(),
+        _ => Err(anyhow!(
+            "lookup table {} must be a JSON array or object",
+            path.display()
+        )),
+    }
+}
@@ -1,19 +1,43 @@
+mod aggregate;
 mod amqp;
 mod config;
+mod expr;
+mod filter;
+mod lookup;
 mod messaging;
 mod metrics;
+mod money;
+mod ratelimit;
+mod routing;
+mod script;
+mod transform;
+mod validation;
+mod wasm;
+mod watcher;
 
-use anyhow::Result;
-use axum::{routing::get, Router};
+use anyhow::{anyhow, Result};
+use axum::{http::StatusCode, routing::get, Json, Router};
 use std::sync::Arc;
-use tokio::{net::TcpListener, signal};
+use std::time::Duration;
+use tokio::{net::TcpListener, signal, sync::watch};
 use tracing::{error, info, Level};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+use aggregate::WindowAggregator;
 use amqp::AMQPConnection;
-use config::AppConfig;
-use messaging::{AMQPConsumer, AMQPPublisher, QueueProcessor};
+use config::{AppConfig, DeliveryGuarantee, LayerConfig, UnknownFields};
+use filter::MessageFilter;
+use messaging::{
+    breaker::CircuitBreaker, consumer::MessageHandler, layer::HandlerStack, AMQPConsumer,
+    AMQPPublisher, OutputStage, QueueProcessor,
+};
 use metrics::Metrics;
+use money::MoneyPolicy;
+use ratelimit::{RateLimitControl, RateLimitsUpdate};
+use routing::MessageRouter;
+use script::ScriptRunner;
+use transform::TransformPipeline;
+use wasm::WasmHandler;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -49,34 +73,160 @@ async fn main() -> Result<()> {
     // Update connection metrics
     app_metrics.set_amqp_connections(1.0);
 
-    // Create channels
-    let publisher_channel = connection.create_channel().await?;
-    let consumer_channel = connection
-        .create_channel_with_qos(config.amqp.prefetch_count)
-        .await?;
+    // Create channels. Transactions only cover one channel, so in
+    // transactional mode the consumer and publisher share it
+    let (consumer_channel, publisher_channel) = match config.pipeline.delivery_guarantee {
+        DeliveryGuarantee::Transactional => {
+            let channel = connection
+                .create_transactional_channel(config.amqp.prefetch_count)
+                .await?;
+            (channel.clone(), channel)
+        }
+        _ => (
+            connection
+                .create_channel_with_qos(config.amqp.prefetch_count)
+                .await?,
+            connection.create_confirm_channel().await?,
+        ),
+    };
+
+    // Rate limits, adjustable at runtime over HTTP
+    let rate_limits =
+        RateLimitControl::from_config(&config.pipeline.rate_limits, app_metrics.clone())?;
+
+    // Circuit breaker shared by the publisher, which trips it, and the
+    // consumer, which pauses while it is open
+    let breaker = config
+        .pipeline
+        .circuit_breaker
+        .as_ref()
+        .map(|breaker_config| CircuitBreaker::new(breaker_config, app_metrics.clone()));
 
     // Setup publisher
-    let publisher = AMQPPublisher::new(publisher_channel);
+    let publisher = AMQPPublisher::new(publisher_channel)
+        .with_rate_limiter(rate_limits.publish.clone())
+        .with_circuit_breaker(breaker.clone());
 
-    // Setup consumer
+    // Build output stage: filter, route and publish
+    let output = OutputStage::new(
+        publisher.clone(),
+        app_metrics.clone(),
+        &config.queues.output_queue,
+    )
+    .with_router(MessageRouter::from_config(
+        &config.pipeline.routing,
+        &config.queues.output_queue,
+    )?)
+    .with_filter(MessageFilter::from_config(&config.pipeline.filters)?);
+
+    // Setup consumer. It reports ready while subscribed to the input queue
+    let (ready_tx, ready) = watch::channel(false);
     let consumer = AMQPConsumer::new(
         consumer_channel,
-        publisher.clone(),
+        output.clone(),
         app_metrics.clone(),
         config.amqp.concurrent,
+    )
+    .with_partial_failure_policy(config.pipeline.on_partial_failure)
+    .with_delivery_guarantee(config.pipeline.delivery_guarantee)
+    .with_ack_mode(config.pipeline.ack_mode, config.pipeline.ack_batch.clone())
+    .with_consumer_config(config.amqp.consumer.clone(), &config.pipeline.name)
+    .with_readiness(ready_tx)
+    .with_handler_metrics(
+        config
+            .pipeline
+            .layers
+            .iter()
+            .any(|layer| matches!(layer, LayerConfig::Metrics)),
+    )
+    .with_timeout(config.pipeline.message_timeout_ms.map(Duration::from_millis))
+    .with_batching(config.pipeline.batch.clone())
+    .with_ordering(config.pipeline.ordering.clone())
+    .with_adaptive_concurrency(config.pipeline.adaptive_concurrency.clone())
+    .with_rate_limiter(rate_limits.consume.clone())
+    .with_circuit_breaker(breaker)
+    .with_blocked_signal(connection.watch_blocked(app_metrics.clone()));
+
+    connection.watch_queue_depth(
+        vec![config.queues.input_queue.clone(), config.queues.output_queue.clone()],
+        app_metrics.clone(),
     );
 
+    // Money and unknown field handling belong to the typed forwarding path,
+    // which any other handler replaces
+    let pipeline = &config.pipeline;
+    if pipeline.money.is_some() || pipeline.unknown_fields != UnknownFields::Drop {
+        let replaced_by = [
+            (!pipeline.transforms.is_empty(), "transforms"),
+            (pipeline.script.is_some(), "script"),
+            (pipeline.wasm.is_some(), "wasm"),
+            (pipeline.aggregate.is_some(), "aggregate"),
+        ]
+        .into_iter()
+        .find_map(|(configured, name)| configured.then_some(name));
+        if let Some(name) = replaced_by {
+            return Err(anyhow!(
+                "`money` and `unknown_fields` only apply to the typed forwarding path and can't be combined with `{}`",
+                name
+            ));
+        }
+    }
+
+    // Build transform pipeline
+    let transforms = TransformPipeline::from_config(&config.pipeline.transforms)?;
+
     // Setup queue processor
-    let processor = QueueProcessor::new(
+    let mut processor = QueueProcessor::new(
         consumer,
         config.queues.input_queue.clone(),
         config.queues.output_queue.clone(),
-    );
+    )
+    .with_transforms(transforms)
+    .with_money(
+        config
+            .pipeline
+            .money
+            .as_ref()
+            .map(MoneyPolicy::from_config)
+            .transpose()?,
+    )
+    .with_unknown_fields(config.pipeline.unknown_fields);
+
+    if let Some(script_config) = &config.pipeline.script {
+        processor = processor.with_script(ScriptRunner::load(script_config)?);
+    }
+
+    // Select message handler
+    let handler: Arc<dyn MessageHandler> = if let Some(wasm_config) = &config.pipeline.wasm {
+        Arc::new(WasmHandler::load(wasm_config, config.amqp.concurrent)?)
+    } else if let Some(aggregate_config) = &config.pipeline.aggregate {
+        // Window summaries are published long after their inputs are acked
+        if config.pipeline.delivery_guarantee == DeliveryGuarantee::Transactional {
+            return Err(anyhow!("aggregation doesn't support the transactional delivery guarantee"));
+        }
+        let aggregator = WindowAggregator::open(aggregate_config)?;
+        aggregator.spawn_closer(
+            output.clone(),
+            Duration::from_millis(aggregate_config.close_interval_ms),
+        );
+        Arc::new(aggregator)
+    } else {
+        Arc::new(processor.clone())
+    };
+    let handler = HandlerStack::from_config(
+        &config.pipeline.layers,
+        &config.queues.input_queue,
+        app_metrics.clone(),
+    )?
+    .wrap(handler);
 
     info!(
         input_queue = %config.queues.input_queue,
         output_queue = %config.queues.output_queue,
         concurrency = config.amqp.concurrent,
+        pipeline = %config.pipeline.name,
+        transforms = config.pipeline.transforms.len(),
+        layers = config.pipeline.layers.len(),
         http_port = config.app.port,
         "Queue processor started successfully"
     );
@@ -97,7 +247,16 @@ async fn main() -> Result<()> {
                         }
                     }
                 }
-            }));
+            }))
+            .route(
+                "/ready",
+                get(move || async move {
+                    match *ready.borrow() {
+                        true => (StatusCode::OK, "ready"),
+                        false => (StatusCode::SERVICE_UNAVAILABLE, "not ready"),
+                    }
+                }),
+            );
 
         let addr = format!("0.0.0.0:{}", metrics_port);
         let listener = match TcpListener::bind(&addr).await {
@@ -115,13 +274,47 @@ async fn main() -> Result<()> {
         }
     });
 
+    // Admin endpoints listen separately, on localhost unless configured
+    let admin_addr = config.app.admin_addr.clone();
+    let admin_handle = tokio::spawn(async move {
+        let app = Router::new().route(
+            "/rate-limits",
+            get({
+                let control = rate_limits.clone();
+                || async move { Json(control.current()) }
+            })
+            .put({
+                let control = rate_limits;
+                |Json(update): Json<RateLimitsUpdate>| async move {
+                    control
+                        .update(&update)
+                        .map(Json)
+                        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+                }
+            }),
+        );
+
+        let listener = match TcpListener::bind(&admin_addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind admin server: {}", e);
+                return;
+            }
+        };
+
+        info!("Admin server listening on {}", admin_addr);
+
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("Admin server error: {}", e);
+        }
+    });
+
     // Start consuming messages
     let consumer_handle = {
-        let processor_clone = processor.clone();
         let consumer_clone = processor.consumer.clone();
         let input_queue = config.queues.input_queue.clone();
         tokio::spawn(async move {
-            if let Err(e) = consumer_clone.start_consuming(&input_queue, processor_clone).await {
+            if let Err(e) = consumer_clone.start_consuming(&input_queue, handler).await {
                 error!("Consumer error: {}", e);
             }
         })
@@ -135,6 +328,7 @@ async fn main() -> Result<()> {
     // Graceful shutdown
     consumer_handle.abort();
     metrics_handle.abort();
+    admin_handle.abort();
     info!("Application shutdown complete");
 
     Ok(())
@@ -0,0 +1,303 @@
+use anyhow::Result;
+use lapin::{message::Delivery, options::*, Channel};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::error;
+
+use super::consumer::settle_partial_failure;
+use crate::config::{AckBatchConfig, AckMode, PartialFailurePolicy};
+use crate::metrics::Metrics;
+
+/// Settles deliveries according to `ack_mode`: not at all under auto-ack,
+/// one ack per delivery, or held back and sent as `multiple` acks.
+#[derive(Clone)]
+pub(super) struct Acknowledger {
+    mode: AckMode,
+    metrics: Arc<Metrics>,
+    batch: Option<Arc<AckBatch>>,
+}
+
+impl Acknowledger {
+    pub(super) fn new(
+        mode: AckMode,
+        channel: Arc<Channel>,
+        config: &AckBatchConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let batch = match mode {
+            AckMode::Batched => Some(AckBatch::spawn(channel, config, metrics.clone())),
+            AckMode::Auto | AckMode::Manual => None,
+        };
+
+        Self {
+            mode,
+            metrics,
+            batch,
+        }
+    }
+
+    /// Note a delivery as it arrives, before it is handed to a worker. A
+    /// batched `multiple` ack never covers a delivery that is still being
+    /// processed.
+    pub(super) async fn received(&self, delivery: &Delivery) {
+        if let Some(batch) = &self.batch {
+            batch.pending.lock().await.received(delivery.delivery_tag);
+        }
+    }
+
+    pub(super) async fn ack(&self, delivery: &Delivery) -> Result<()> {
+        match (&self.mode, &self.batch) {
+            (AckMode::Auto, _) => Ok(()),
+            (AckMode::Batched, Some(batch)) => batch.ack(delivery.delivery_tag).await,
+            _ => {
+                let start = Instant::now();
+                delivery.ack(BasicAckOptions::default()).await?;
+                self.metrics.observe_ack_latency(start.elapsed());
+                self.metrics.observe_ack_batch_size(1);
+                Ok(())
+            }
+        }
+    }
+
+    /// Settle a delivery that failed according to `policy`. Nacks are never
+    /// batched.
+    pub(super) async fn settle_failure(
+        &self,
+        delivery: &Delivery,
+        policy: PartialFailurePolicy,
+    ) -> Result<()> {
+        match (&self.mode, &self.batch, policy) {
+            (AckMode::Auto, _, _) => Ok(()),
+            (_, _, PartialFailurePolicy::Ack) => self.ack(delivery).await,
+            (AckMode::Batched, Some(batch), _) => {
+                batch
+                    .nack(delivery, policy == PartialFailurePolicy::Requeue)
+                    .await
+            }
+            _ => settle_partial_failure(delivery, policy, false).await,
+        }
+    }
+}
+
+/// Acks waiting to be sent. Deliveries finish out of order, so a
+/// `multiple` ack can only go up to the lowest delivery still being
+/// processed.
+struct AckBatch {
+    channel: Arc<Channel>,
+    metrics: Arc<Metrics>,
+    max_messages: usize,
+    max_delay: Duration,
+    pending: Mutex<PendingAcks>,
+}
+
+/// Delivery tags between receipt and ack. Tags that have been acked or
+/// nacked are forgotten, so both sets stay bounded by the prefetch count.
+#[derive(Default)]
+struct PendingAcks {
+    /// Received but not yet acked or nacked.
+    unsettled: BTreeSet<u64>,
+    /// Ready to ack, with when they became ready.
+    waiting: BTreeMap<u64, Instant>,
+}
+
+/// Acks to send, taken out of `PendingAcks`.
+#[derive(Debug, Default, PartialEq)]
+struct ReadyAcks {
+    /// Tag for one `multiple` ack, and when each delivery it covers became
+    /// ready.
+    multiple: Option<(u64, Vec<Instant>)>,
+    /// Overdue deliveries past a gap, acked one by one.
+    single: Vec<(u64, Instant)>,
+}
+
+impl PendingAcks {
+    fn received(&mut self, tag: u64) {
+        self.unsettled.insert(tag);
+    }
+
+    fn acked(&mut self, tag: u64, now: Instant) {
+        self.unsettled.remove(&tag);
+        self.waiting.insert(tag, now);
+    }
+
+    fn nacked(&mut self, tag: u64) {
+        self.unsettled.remove(&tag);
+    }
+
+    /// Take every waiting ack below the lowest unsettled delivery. With
+    /// `overdue`, also take acks past it that have waited since before that
+    /// instant, so a delivery that is never settled doesn't hold back every
+    /// ack after it.
+    fn take_ready(&mut self, overdue: Option<Instant>) -> ReadyAcks {
+        let mut ready = ReadyAcks::default();
+
+        let covered = match self.unsettled.first() {
+            Some(&gap) => {
+                let rest = self.waiting.split_off(&gap);
+                std::mem::replace(&mut self.waiting, rest)
+            }
+            None => std::mem::take(&mut self.waiting),
+        };
+        if let Some((&last, _)) = covered.last_key_value() {
+            ready.multiple = Some((last, covered.into_values().collect()));
+        }
+
+        if let Some(cutoff) = overdue {
+            self.waiting.retain(|&tag, &mut since| {
+                let overdue = since <= cutoff;
+                if overdue {
+                    ready.single.push((tag, since));
+                }
+                !overdue
+            });
+        }
+
+        ready
+    }
+}
+
+impl AckBatch {
+    fn spawn(channel: Arc<Channel>, config: &AckBatchConfig, metrics: Arc<Metrics>) -> Arc<Self> {
+        let batch = Arc::new(Self {
+            channel,
+            metrics,
+            max_messages: config.max_messages.max(1),
+            max_delay: Duration::from_millis(config.max_delay_ms.max(1)),
+            pending: Mutex::new(PendingAcks::default()),
+        });
+
+        let interval = batch.max_delay;
+        let batch_ref = Arc::downgrade(&batch);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(batch) = Weak::upgrade(&batch_ref) else {
+                    break;
+                };
+                let mut pending = batch.pending.lock().await;
+                if !pending.waiting.is_empty() {
+                    if let Err(e) = batch.flush(&mut pending, true).await {
+                        error!(error = %e, "Failed to send batched acks");
+                    }
+                }
+            }
+        });
+
+        batch
+    }
+
+    async fn ack(&self, delivery_tag: u64) -> Result<()> {
+        let mut pending = self.pending.lock().await;
+        pending.acked(delivery_tag, Instant::now());
+
+        if pending.waiting.len() >= self.max_messages {
+            self.flush(&mut pending, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Nack right away, holding the lock so that no `multiple` ack that
+    /// would cover this delivery goes out first.
+    async fn nack(&self, delivery: &Delivery, requeue: bool) -> Result<()> {
+        let mut pending = self.pending.lock().await;
+        delivery
+            .nack(BasicNackOptions {
+                multiple: false,
+                requeue,
+            })
+            .await?;
+        pending.nacked(delivery.delivery_tag);
+        Ok(())
+    }
+
+    /// Send one `multiple` ack for everything below the lowest unsettled
+    /// delivery. With `overdue`, also ack one by one anything past it that
+    /// has waited a full interval.
+    async fn flush(&self, pending: &mut PendingAcks, overdue: bool) -> Result<()> {
+        let cutoff = overdue
+            .then(|| Instant::now().checked_sub(self.max_delay))
+            .flatten();
+        let ready = pending.take_ready(cutoff);
+
+        if let Some((tag, ready_at)) = ready.multiple {
+            self.channel
+                .basic_ack(tag, BasicAckOptions { multiple: true })
+                .await?;
+            self.observe(&ready_at);
+        }
+
+        for (tag, since) in ready.single {
+            self.channel
+                .basic_ack(tag, BasicAckOptions { multiple: false })
+                .await?;
+            self.observe(&[since]);
+        }
+
+        Ok(())
+    }
+
+    fn observe(&self, ready: &[Instant]) {
+        self.metrics.observe_ack_batch_size(ready.len());
+        for ready_at in ready {
+            self.metrics.observe_ack_latency(ready_at.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_acks_behind_a_gap_until_it_is_settled() {
+        let start = Instant::now();
+        let mut pending = PendingAcks::default();
+        for tag in 1..=5 {
+            pending.received(tag);
+        }
+
+        pending.acked(3, start);
+        pending.acked(2, start);
+        assert_eq!(pending.take_ready(None), ReadyAcks::default());
+
+        pending.acked(1, start);
+        let ready = pending.take_ready(None);
+        assert_eq!(ready.multiple, Some((3, vec![start; 3])));
+        assert!(ready.single.is_empty());
+
+        pending.acked(5, start);
+        assert_eq!(pending.take_ready(None), ReadyAcks::default());
+
+        pending.nacked(4);
+        assert_eq!(pending.take_ready(None).multiple, Some((5, vec![start])));
+        assert!(pending.unsettled.is_empty() && pending.waiting.is_empty());
+    }
+
+    #[test]
+    fn acks_overdue_deliveries_past_a_gap_one_by_one() {
+        let start = Instant::now();
+        let later = start + Duration::from_millis(100);
+        let mut pending = PendingAcks::default();
+        for tag in 1..=4 {
+            pending.received(tag);
+        }
+
+        pending.acked(2, start);
+        pending.acked(3, later);
+        pending.acked(4, start);
+        let ready = pending.take_ready(Some(start));
+        assert_eq!(ready.multiple, None);
+        assert_eq!(ready.single, vec![(2, start), (4, start)]);
+
+        // Acked tags are forgotten, so a gap that never fills holds nothing
+        // but the deliveries still waiting
+        assert_eq!(pending.waiting.keys().copied().collect::<Vec<_>>(), vec![3]);
+        assert_eq!(pending.unsettled.iter().copied().collect::<Vec<_>>(), vec![1]);
+
+        pending.acked(1, later);
+        assert_eq!(pending.take_ready(None).multiple, Some((3, vec![later, later])));
+    }
+}
@@ -0,0 +1,373 @@
+use anyhow::{anyhow, Result};
+use futures_util::future::join_all;
+use futures_util::stream::{Stream, StreamExt};
+use lapin::message::Delivery;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use super::consumer::{message_id, settle_partial_failure, within, MessageHandler};
+use super::headers::headers_to_json;
+use super::output::{OutputStage, RoutedMessage};
+use crate::config::{BatchConfig, PartialFailurePolicy};
+use crate::metrics::Metrics;
+use crate::routing::Destination;
+
+/// Outputs collected since the last flush, grouped by destination.
+#[derive(Default)]
+struct PendingBatch {
+    groups: HashMap<Destination, Vec<Value>>,
+    last_delivery: Option<Delivery>,
+    inputs: usize,
+    started: Option<Instant>,
+}
+
+impl PendingBatch {
+    /// Add a delivery whose outputs all routed. The first one starts the
+    /// `max_wait` clock.
+    fn push(&mut self, delivery: Delivery, routed: Vec<(Destination, Value)>, now: Instant) {
+        for (destination, body) in routed {
+            self.groups.entry(destination).or_default().push(body);
+        }
+        self.started.get_or_insert(now);
+        self.inputs += 1;
+        self.last_delivery = Some(delivery);
+    }
+
+    fn is_full(&self, max_messages: usize) -> bool {
+        self.inputs >= max_messages
+    }
+
+    /// When the batch flushes even if it isn't full; never while empty.
+    fn deadline(&self, max_wait: Duration) -> Option<Instant> {
+        self.started.map(|started| started + max_wait)
+    }
+}
+
+/// How to settle a delivery. With `multiple`, every unsettled delivery up
+/// to it is settled the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Settlement {
+    policy: PartialFailurePolicy,
+    multiple: bool,
+}
+
+impl Settlement {
+    /// An input whose handler or routing failed is rejected on its own right
+    /// away, before a later multi-ack can cover it.
+    const FAILED_INPUT: Self = Self {
+        policy: PartialFailurePolicy::Reject,
+        multiple: false,
+    };
+
+    /// A flushed batch settles all of its inputs through its last delivery:
+    /// acked once every group is published, otherwise by `partial_failure`.
+    fn batch(published: bool, partial_failure: PartialFailurePolicy) -> Self {
+        Self {
+            policy: if published {
+                PartialFailurePolicy::Ack
+            } else {
+                partial_failure
+            },
+            multiple: true,
+        }
+    }
+
+    async fn apply(self, delivery: &Delivery) -> Result<()> {
+        settle_partial_failure(delivery, self.policy, self.multiple).await
+    }
+}
+
+/// Consumption loop for batch mode. Handlers still run concurrently, but
+/// their results are taken in delivery order so that every delivery up to
+/// the last one in a batch is settled before the batch is multi-acked.
+pub struct BatchConsumer<H> {
+    handler: H,
+    output: OutputStage,
+    metrics: Arc<Metrics>,
+    concurrency: usize,
+    config: BatchConfig,
+    partial_failure: PartialFailurePolicy,
+    timeout: Option<Duration>,
+    handler_metrics: bool,
+}
+
+impl<H> BatchConsumer<H>
+where
+    H: MessageHandler + Clone + 'static,
+{
+    pub fn new(
+        handler: H,
+        output: OutputStage,
+        metrics: Arc<Metrics>,
+        concurrency: usize,
+        config: BatchConfig,
+        partial_failure: PartialFailurePolicy,
+    ) -> Self {
+        Self {
+            handler,
+            output,
+            metrics,
+            concurrency,
+            config,
+            partial_failure,
+            timeout: None,
+            handler_metrics: true,
+        }
+    }
+
+    /// Fail a delivery whose handler takes longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether the metrics layer already counts handler failures.
+    pub fn with_handler_metrics(mut self, handler_metrics: bool) -> Self {
+        self.handler_metrics = handler_metrics;
+        self
+    }
+
+    pub async fn run<S>(self, consumer: S) -> Result<()>
+    where
+        S: Stream<Item = lapin::Result<Delivery>> + Unpin,
+    {
+        let handler = self.handler.clone();
+        let metrics = self.metrics.clone();
+        let timeout = self.timeout;
+        let handler_metrics = self.handler_metrics;
+
+        let mut results = std::pin::pin!(consumer
+            .filter_map(|delivery| async move {
+                delivery
+                    .map_err(|e| error!(error = %e, "Failed to consume message"))
+                    .ok()
+            })
+            .map(move |delivery| {
+                let handler = handler.clone();
+                let metrics = metrics.clone();
+                async move {
+                    let start = std::time::Instant::now();
+                    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+                    let result = match within(deadline, handler.handle(&delivery)).await {
+                        Some(result) => {
+                            if result.is_err() && !handler_metrics {
+                                metrics.inc_messages_failed();
+                            }
+                            result
+                        }
+                        None => {
+                            metrics.inc_messages_failed();
+                            metrics.inc_message_timeouts("handle");
+                            warn!(
+                                message_id = %message_id(&delivery),
+                                stage = "handle",
+                                elapsed_ms = start.elapsed().as_millis(),
+                                "Message processing timed out"
+                            );
+                            Err(anyhow!("handler timed out"))
+                        }
+                    };
+                    (delivery, result)
+                }
+            })
+            .buffered(self.concurrency));
+
+        info!(
+            max_messages = self.config.max_messages,
+            max_wait_ms = self.config.max_wait_ms,
+            format = ?self.config.format,
+            "Batch mode enabled"
+        );
+
+        let max_wait = Duration::from_millis(self.config.max_wait_ms);
+        let mut batch = PendingBatch::default();
+
+        loop {
+            let deadline = batch.deadline(max_wait);
+
+            tokio::select! {
+                next = results.next() => match next {
+                    Some((delivery, result)) => {
+                        self.add(&mut batch, delivery, result).await;
+                        if batch.is_full(self.config.max_messages) {
+                            self.flush(&mut batch).await;
+                        }
+                    }
+                    None => {
+                        self.flush(&mut batch).await;
+                        return Ok(());
+                    }
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.flush(&mut batch).await;
+                }
+            }
+        }
+    }
+
+    async fn add(&self, batch: &mut PendingBatch, delivery: Delivery, result: Result<Vec<RoutedMessage>>) {
+        let headers = headers_to_json(&delivery.properties);
+        // Handler failures and timeouts were counted as they happened
+        let routed = result.and_then(|outputs| {
+            self.output
+                .route_all(outputs, &headers)
+                .inspect_err(|_| self.metrics.inc_messages_failed())
+        });
+
+        match routed {
+            Ok(routed) => batch.push(delivery, routed, Instant::now()),
+            Err(e) => {
+                error!(error = %e, "Message processing failed, rejecting it");
+
+                if let Err(e) = Settlement::FAILED_INPUT.apply(&delivery).await {
+                    error!(error = %e, "Failed to settle message after processing failure");
+                }
+            }
+        }
+    }
+
+    async fn flush(&self, batch: &mut PendingBatch) {
+        let PendingBatch {
+            groups,
+            last_delivery,
+            inputs,
+            started,
+        } = std::mem::take(batch);
+
+        let Some(last_delivery) = last_delivery else {
+            return;
+        };
+
+        let results = join_all(groups.iter().map(|(destination, bodies)| {
+            self.output
+                .publish_batch(destination, bodies, self.config.format)
+        }))
+        .await;
+        let errors: Vec<String> = results
+            .into_iter()
+            .filter_map(|result| result.err().map(|e| e.to_string()))
+            .collect();
+
+        let settlement = Settlement::batch(errors.is_empty(), self.partial_failure);
+        if errors.is_empty() {
+            match settlement.apply(&last_delivery).await {
+                Ok(()) => {
+                    self.metrics.inc_messages_processed_by(inputs as u64);
+                    let latency = started.map(|started| started.elapsed()).unwrap_or_default();
+                    self.metrics.observe_batch(inputs, latency);
+
+                    info!(
+                        inputs = inputs,
+                        destinations = groups.len(),
+                        latency_ms = latency.as_millis(),
+                        "Batch published"
+                    );
+                }
+                Err(e) => error!(error = %e, "Failed to acknowledge batch"),
+            }
+        } else {
+            self.metrics.inc_messages_failed_by(inputs as u64);
+            error!(
+                inputs = inputs,
+                error = %errors.join("; "),
+                policy = ?self.partial_failure,
+                "Failed to publish batch"
+            );
+
+            if let Err(e) = settlement.apply(&last_delivery).await {
+                error!(error = %e, "Failed to settle batch after publish failure");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::acker::Acker;
+    use lapin::BasicProperties;
+    use serde_json::json;
+
+    fn delivery(tag: u64) -> Delivery {
+        Delivery {
+            delivery_tag: tag,
+            exchange: "".into(),
+            routing_key: "input".into(),
+            redelivered: false,
+            properties: BasicProperties::default(),
+            data: b"{}".to_vec(),
+            acker: Acker::default(),
+        }
+    }
+
+    fn destination(routing_key: &str) -> Destination {
+        Destination {
+            route: "default".to_string(),
+            exchange: String::new(),
+            routing_key: routing_key.to_string(),
+        }
+    }
+
+    #[test]
+    fn flushes_when_full_or_after_max_wait() {
+        let start = Instant::now();
+        let max_wait = Duration::from_millis(100);
+        let mut batch = PendingBatch::default();
+        assert_eq!(batch.deadline(max_wait), None);
+
+        batch.push(delivery(1), vec![(destination("a"), json!(1))], start);
+        batch.push(delivery(2), vec![], start + Duration::from_millis(50));
+        assert!(!batch.is_full(3));
+        // The clock runs from the first input, not the latest
+        assert_eq!(batch.deadline(max_wait), Some(start + max_wait));
+
+        batch.push(delivery(3), vec![(destination("a"), json!(3))], start);
+        assert!(batch.is_full(3));
+    }
+
+    #[test]
+    fn settles_a_flushed_batch_through_its_last_delivery() {
+        let mut batch = PendingBatch::default();
+        batch.push(delivery(1), vec![(destination("a"), json!(1))], Instant::now());
+        batch.push(
+            delivery(2),
+            vec![(destination("b"), json!(2)), (destination("a"), json!(3))],
+            Instant::now(),
+        );
+
+        assert_eq!(batch.inputs, 2);
+        assert_eq!(batch.last_delivery.as_ref().map(|d| d.delivery_tag), Some(2));
+        assert_eq!(batch.groups[&destination("a")], vec![json!(1), json!(3)]);
+        assert_eq!(batch.groups[&destination("b")], vec![json!(2)]);
+
+        assert_eq!(
+            Settlement::batch(true, PartialFailurePolicy::Requeue),
+            Settlement {
+                policy: PartialFailurePolicy::Ack,
+                multiple: true
+            }
+        );
+        assert_eq!(
+            Settlement::batch(false, PartialFailurePolicy::Requeue),
+            Settlement {
+                policy: PartialFailurePolicy::Requeue,
+                multiple: true
+            }
+        );
+    }
+
+    #[test]
+    fn settles_failed_inputs_one_by_one() {
+        assert_eq!(
+            Settlement::FAILED_INPUT,
+            Settlement {
+                policy: PartialFailurePolicy::Reject,
+                multiple: false
+            }
+        );
+    }
+}
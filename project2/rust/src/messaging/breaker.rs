@@ -0,0 +1,236 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::config::CircuitBreakerConfig;
+use crate::metrics::Metrics;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+struct Inner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    probe_started: Option<Instant>,
+}
+
+/// Circuit breaker around publishing. Consecutive publish failures open it,
+/// which stops the consumer from pulling deliveries. After `open_duration_ms`
+/// a single delivery is let through as a probe: if its publish succeeds the
+/// breaker closes, otherwise it opens again.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+    changed: watch::Sender<BreakerState>,
+    metrics: Arc<Metrics>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig, metrics: Arc<Metrics>) -> Arc<Self> {
+        metrics.set_circuit_breaker_state(BreakerState::Closed.as_str());
+
+        Arc::new(Self {
+            config: config.clone(),
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: Instant::now(),
+                probe_started: None,
+            }),
+            changed: watch::channel(BreakerState::Closed).0,
+            metrics,
+        })
+    }
+
+    fn open_duration(&self) -> Duration {
+        Duration::from_millis(self.config.open_duration_ms)
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = 0;
+        if inner.state != BreakerState::Closed {
+            self.transition(&mut inner, BreakerState::Closed);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures += 1;
+
+        let trip = match inner.state {
+            BreakerState::Closed => inner.consecutive_failures >= self.config.failure_threshold,
+            BreakerState::HalfOpen => true,
+            BreakerState::Open => false,
+        };
+        if trip {
+            inner.opened_at = Instant::now();
+            self.transition(&mut inner, BreakerState::Open);
+        }
+    }
+
+    /// Wait until the consumer may take another delivery. Returns at once
+    /// while closed; while open, waits out the open period and then lets
+    /// one probe through.
+    pub async fn wait_ready(&self) {
+        loop {
+            let mut changed = self.changed.subscribe();
+
+            let wait = {
+                let mut inner = self.inner.lock().unwrap();
+                match inner.state {
+                    BreakerState::Closed => return,
+                    BreakerState::Open => {
+                        let elapsed = inner.opened_at.elapsed();
+                        if elapsed >= self.open_duration() {
+                            self.transition(&mut inner, BreakerState::HalfOpen);
+                            inner.probe_started = Some(Instant::now());
+                            return;
+                        }
+                        self.open_duration() - elapsed
+                    }
+                    BreakerState::HalfOpen => {
+                        // A probe that never publishes (filtered, or no
+                        // outputs) can't settle the state, so allow another
+                        // one after an open period.
+                        let stalled = inner
+                            .probe_started
+                            .is_none_or(|at| at.elapsed() >= self.open_duration());
+                        if stalled {
+                            inner.probe_started = Some(Instant::now());
+                            return;
+                        }
+                        self.open_duration()
+                    }
+                }
+            };
+
+            tokio::select! {
+                _ = changed.changed() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: BreakerState) {
+        let from = inner.state;
+        inner.state = to;
+
+        match to {
+            BreakerState::Open => warn!(
+                from = from.as_str(),
+                failures = inner.consecutive_failures,
+                open_ms = self.config.open_duration_ms,
+                "Circuit breaker opened; pausing consumption"
+            ),
+            BreakerState::HalfOpen => info!(
+                from = from.as_str(),
+                "Circuit breaker half-open; probing with one message"
+            ),
+            BreakerState::Closed => info!(
+                from = from.as_str(),
+                "Circuit breaker closed; resuming consumption"
+            ),
+        }
+
+        self.metrics
+            .inc_circuit_breaker_transitions(from.as_str(), to.as_str());
+        self.metrics.set_circuit_breaker_state(to.as_str());
+        self.changed.send_replace(to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPEN: Duration = Duration::from_millis(50);
+
+    fn breaker(failure_threshold: u32) -> Arc<CircuitBreaker> {
+        let config = CircuitBreakerConfig {
+            failure_threshold,
+            open_duration_ms: OPEN.as_millis() as u64,
+        };
+        CircuitBreaker::new(&config, Arc::new(Metrics::new()))
+    }
+
+    fn state(breaker: &CircuitBreaker) -> BreakerState {
+        breaker.inner.lock().unwrap().state
+    }
+
+    /// Whether `wait_ready` returns within `within`.
+    async fn ready_within(breaker: &CircuitBreaker, within: Duration) -> bool {
+        tokio::time::timeout(within, breaker.wait_ready()).await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures() {
+        let breaker = breaker(3);
+
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        assert!(ready_within(&breaker, Duration::ZERO).await);
+
+        breaker.record_failure();
+        assert_eq!(state(&breaker), BreakerState::Open);
+    }
+
+    #[tokio::test]
+    async fn holds_deliveries_while_open() {
+        let breaker = breaker(1);
+        breaker.record_failure();
+
+        assert!(!ready_within(&breaker, OPEN / 2).await);
+        assert_eq!(state(&breaker), BreakerState::Open);
+    }
+
+    #[tokio::test]
+    async fn closes_after_a_successful_probe() {
+        let breaker = breaker(1);
+        breaker.record_failure();
+
+        assert!(ready_within(&breaker, OPEN * 4).await);
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+        // Only one probe at a time
+        assert!(!ready_within(&breaker, OPEN / 2).await);
+
+        breaker.record_success();
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        assert!(ready_within(&breaker, Duration::ZERO).await);
+    }
+
+    #[tokio::test]
+    async fn reopens_after_a_failed_probe() {
+        let breaker = breaker(3);
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        assert!(ready_within(&breaker, OPEN * 4).await);
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+
+        // A single failure is enough while half-open
+        breaker.record_failure();
+        assert_eq!(state(&breaker), BreakerState::Open);
+        assert!(!ready_within(&breaker, OPEN / 2).await);
+    }
+}
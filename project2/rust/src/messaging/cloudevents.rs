@@ -0,0 +1,307 @@
+//! CloudEvents 1.0 AMQP protocol binding.
+//!
+//! Structured mode carries the whole event as a JSON envelope with content
+//! type `application/cloudevents+json`. Binary mode carries the attributes
+//! as application properties named `cloudEvents:<attribute>` and the data as
+//! the message body.
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lapin::message::Delivery;
+use lapin::types::{AMQPValue, FieldTable, LongString};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::consumer::MessageHandler;
+use super::headers::headers_to_json;
+use super::layer::Layer;
+use super::output::RoutedMessage;
+use crate::config::{CloudEventsConfig, CloudEventsMode};
+use crate::routing::render_template;
+
+pub const STRUCTURED_CONTENT_TYPE: &str = "application/cloudevents+json";
+pub const HEADER_PREFIX: &str = "cloudEvents:";
+const SPEC_VERSION: &str = "1.0";
+const DATA_CONTENT_TYPE: &str = "application/json";
+
+/// Wraps every output of the inner handler as a CloudEvent and, unless
+/// disabled, unwraps structured-mode input first so the handler sees only
+/// the event data. The unwrapped attributes are passed on as
+/// `cloudEvents:` headers, the same shape binary-mode input already has.
+pub struct CloudEventsLayer {
+    config: Arc<CloudEventsConfig>,
+}
+
+impl CloudEventsLayer {
+    pub fn new(config: &CloudEventsConfig) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+        }
+    }
+}
+
+impl Layer for CloudEventsLayer {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        Arc::new(CloudEventsHandler {
+            inner,
+            config: self.config.clone(),
+        })
+    }
+}
+
+struct CloudEventsHandler {
+    inner: Arc<dyn MessageHandler>,
+    config: Arc<CloudEventsConfig>,
+}
+
+#[async_trait]
+impl MessageHandler for CloudEventsHandler {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let unwrapped = match self.config.unwrap_input && is_structured(delivery) {
+            true => Some(unwrap_structured(delivery)?),
+            false => None,
+        };
+        let delivery = unwrapped.as_ref().unwrap_or(delivery);
+
+        let headers = headers_to_json(&delivery.properties);
+        let mut outputs = self.inner.handle(delivery).await?;
+        for output in &mut outputs {
+            self.wrap(output, &headers)?;
+        }
+
+        Ok(outputs)
+    }
+}
+
+impl CloudEventsHandler {
+    fn attributes(&self, data: &Value, headers: &Value) -> Result<Map<String, Value>> {
+        let config = &self.config;
+        let render = |template: &str| -> Result<Value> {
+            Ok(Value::String(render_template(template, data, headers)?))
+        };
+
+        let mut attributes = Map::new();
+        attributes.insert("specversion".into(), SPEC_VERSION.into());
+        attributes.insert(
+            "id".into(),
+            match &config.id {
+                Some(id) => render(id)?,
+                None => Uuid::new_v4().to_string().into(),
+            },
+        );
+        attributes.insert("source".into(), render(&config.source)?);
+        attributes.insert("type".into(), render(&config.event_type)?);
+        attributes.insert(
+            "time".into(),
+            match &config.time {
+                Some(time) => render(time)?,
+                None => chrono::Utc::now()
+                    .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                    .into(),
+            },
+        );
+        if let Some(subject) = &config.subject {
+            attributes.insert("subject".into(), render(subject)?);
+        }
+        attributes.insert("datacontenttype".into(), DATA_CONTENT_TYPE.into());
+
+        Ok(attributes)
+    }
+
+    fn wrap(&self, output: &mut RoutedMessage, headers: &Value) -> Result<()> {
+        let attributes = self.attributes(&output.body, headers)?;
+
+        match self.config.mode {
+            CloudEventsMode::Structured => {
+                let mut event = attributes;
+                event.insert("data".into(), output.body.take());
+                output.body = Value::Object(event);
+                output.content_type = Some(STRUCTURED_CONTENT_TYPE.to_string());
+            }
+            CloudEventsMode::Binary => {
+                // datacontenttype maps onto the AMQP content-type property
+                for (name, value) in attributes {
+                    if name != "datacontenttype" {
+                        output.headers.insert(format!("{}{}", HEADER_PREFIX, name), value);
+                    }
+                }
+                output.content_type = Some(DATA_CONTENT_TYPE.to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_structured(delivery: &Delivery) -> bool {
+    delivery
+        .properties
+        .content_type()
+        .as_ref()
+        .is_some_and(|content_type| content_type.as_str().starts_with(STRUCTURED_CONTENT_TYPE))
+}
+
+/// Copy of a structured-mode delivery with the event data as its body and
+/// the attributes moved into `cloudEvents:` headers.
+fn unwrap_structured(delivery: &Delivery) -> Result<Delivery> {
+    let mut event: Map<String, Value> = serde_json::from_slice(&delivery.data)
+        .map_err(|e| anyhow!("invalid structured CloudEvent: {}", e))?;
+
+    if event.contains_key("data_base64") {
+        return Err(anyhow!("CloudEvents with data_base64 are not supported"));
+    }
+    let data = event.remove("data").unwrap_or(Value::Null);
+    let content_type = match event.remove("datacontenttype") {
+        Some(Value::String(content_type)) => content_type,
+        _ => DATA_CONTENT_TYPE.to_string(),
+    };
+
+    let mut headers = delivery.properties.headers().clone().unwrap_or_default();
+    insert_attributes(&mut headers, event);
+
+    let data = match data {
+        // Non-JSON data is carried as a string in structured mode
+        Value::String(text) if !content_type.contains("json") => text.into_bytes(),
+        data => serde_json::to_vec(&data)?,
+    };
+
+    Ok(Delivery {
+        delivery_tag: delivery.delivery_tag,
+        exchange: delivery.exchange.clone(),
+        routing_key: delivery.routing_key.clone(),
+        redelivered: delivery.redelivered,
+        properties: delivery
+            .properties
+            .clone()
+            .with_content_type(content_type.into())
+            .with_headers(headers),
+        data,
+        acker: delivery.acker.clone(),
+    })
+}
+
+fn insert_attributes(headers: &mut FieldTable, attributes: Map<String, Value>) {
+    for (name, value) in attributes {
+        let value = match value {
+            Value::String(text) => text,
+            other => other.to_string(),
+        };
+        headers.insert(
+            format!("{}{}", HEADER_PREFIX, name).into(),
+            AMQPValue::LongString(LongString::from(value)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::{acker::Acker, BasicProperties};
+    use serde_json::json;
+
+    /// Returns what it was given: the body, content type and headers.
+    struct Echo;
+
+    #[async_trait]
+    impl MessageHandler for Echo {
+        async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+            let content_type = delivery.properties.content_type().as_ref().map(|c| c.to_string());
+            Ok(vec![RoutedMessage::new(json!({
+                "data": serde_json::from_slice::<Value>(&delivery.data)?,
+                "content_type": content_type,
+                "headers": headers_to_json(&delivery.properties),
+            }))])
+        }
+    }
+
+    fn handler(config: Value) -> Arc<dyn MessageHandler> {
+        let config: CloudEventsConfig = serde_json::from_value(config).unwrap();
+        CloudEventsLayer::new(&config).layer(Arc::new(Echo))
+    }
+
+    fn delivery(content_type: &str, body: Value) -> Delivery {
+        Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "input".into(),
+            redelivered: false,
+            properties: BasicProperties::default().with_content_type(content_type.into()),
+            data: serde_json::to_vec(&body).unwrap(),
+            acker: Acker::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn unwraps_structured_input_and_wraps_outputs() {
+        let handler = handler(json!({
+            "source": "/orders",
+            "event_type": "order.processed",
+            "id": "{data.order_id}",
+            "subject": "{headers.cloudEvents:subject}"
+        }));
+        let input = delivery(
+            STRUCTURED_CONTENT_TYPE,
+            json!({
+                "specversion": "1.0",
+                "id": "e1",
+                "source": "/shop",
+                "type": "order.created",
+                "subject": "u1",
+                "data": {"order_id": 7}
+            }),
+        );
+
+        let outputs = handler.handle(&input).await.unwrap();
+
+        let event = &outputs[0].body;
+        assert_eq!(outputs[0].content_type.as_deref(), Some(STRUCTURED_CONTENT_TYPE));
+        assert_eq!(event["specversion"], "1.0");
+        assert_eq!(event["id"], "7");
+        assert_eq!(event["source"], "/orders");
+        assert_eq!(event["type"], "order.processed");
+        assert_eq!(event["subject"], "u1");
+        assert_eq!(event["datacontenttype"], DATA_CONTENT_TYPE);
+
+        // The inner handler saw only the data, with the attributes as headers
+        let seen = &event["data"];
+        assert_eq!(seen["data"], json!({"order_id": 7}));
+        assert_eq!(seen["content_type"], DATA_CONTENT_TYPE);
+        assert_eq!(seen["headers"]["cloudEvents:id"], "e1");
+        assert_eq!(seen["headers"]["cloudEvents:type"], "order.created");
+    }
+
+    #[tokio::test]
+    async fn wraps_binary_outputs_as_headers() {
+        let handler = handler(json!({
+            "mode": "binary",
+            "source": "/orders",
+            "event_type": "order.processed"
+        }));
+
+        let outputs = handler
+            .handle(&delivery(DATA_CONTENT_TYPE, json!({"order_id": 7})))
+            .await
+            .unwrap();
+
+        let output = &outputs[0];
+        assert_eq!(output.content_type.as_deref(), Some(DATA_CONTENT_TYPE));
+        assert_eq!(output.body["data"], json!({"order_id": 7}));
+        assert_eq!(output.headers["cloudEvents:specversion"], "1.0");
+        assert_eq!(output.headers["cloudEvents:source"], "/orders");
+        assert!(output.headers.contains_key("cloudEvents:id"));
+        assert!(output.headers.contains_key("cloudEvents:time"));
+        assert!(!output.headers.contains_key("cloudEvents:datacontenttype"));
+    }
+
+    #[tokio::test]
+    async fn rejects_base64_data() {
+        let handler = handler(json!({"source": "/orders", "event_type": "order.processed"}));
+        let input = delivery(
+            STRUCTURED_CONTENT_TYPE,
+            json!({"specversion": "1.0", "id": "e1", "data_base64": "e30="}),
+        );
+
+        assert!(handler.handle(&input).await.is_err());
+    }
+}
@@ -1,32 +1,82 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{Stream, StreamExt};
 use lapin::{
     message::Delivery,
     options::*,
-    types::FieldTable,
-    Channel,
+    protocol::{AMQPErrorKind, AMQPSoftError},
+    types::{AMQPValue, FieldTable},
+    Channel, Consumer,
 };
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::future::Future;
 use std::sync::Arc;
-use tokio::sync::Semaphore;
+use std::time::Duration;
+use tokio::sync::{watch, Semaphore};
 use tracing::{error, info, instrument, warn};
 use uuid::Uuid;
 
-use super::publisher::AMQPPublisher;
+use super::ack::Acknowledger;
+use super::batch::BatchConsumer;
+use super::breaker::CircuitBreaker;
+use super::crash::crash_point;
+use super::ordered::OrderedDispatcher;
+use super::headers::headers_to_json;
+use super::limiter::{AdaptiveLimiter, PublishSample};
+use super::output::{OutputStage, RoutedMessage};
+use super::transaction::{Transaction, TransactionalChannel};
+use crate::config::{
+    AckBatchConfig, AckMode, AdaptiveConcurrencyConfig, BatchConfig, ConsumerConfig,
+    DeliveryGuarantee, OrderingConfig, PartialFailurePolicy, UnknownFields,
+};
 use crate::metrics::Metrics;
+use crate::money::MoneyPolicy;
+use crate::ratelimit::RateLimiter;
+use crate::script::ScriptRunner;
+use crate::transform::TransformPipeline;
+
+/// First wait before subscribing again after the broker cancels the
+/// consumer. Doubles while subscribing fails.
+const RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(30);
 
+/// Turns one delivery into zero or more output messages. The consumer
+/// publishes the outputs and acknowledges the delivery only once every
+/// output has been confirmed by the broker.
 #[async_trait]
 pub trait MessageHandler: Send + Sync {
-    async fn handle(&self, delivery: Delivery) -> Result<Delivery>;
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>>;
+}
+
+#[async_trait]
+impl<T: MessageHandler + ?Sized> MessageHandler for Arc<T> {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        (**self).handle(delivery).await
+    }
 }
 
 #[derive(Clone)]
 pub struct AMQPConsumer {
     channel: Arc<Channel>,
-    publisher: AMQPPublisher,
+    output: OutputStage,
     metrics: Arc<Metrics>,
     concurrency: usize,
+    partial_failure: PartialFailurePolicy,
+    batch: Option<BatchConfig>,
+    ordering: Option<OrderingConfig>,
+    adaptive_concurrency: Option<AdaptiveConcurrencyConfig>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    breaker: Option<Arc<CircuitBreaker>>,
+    blocked: Option<watch::Receiver<bool>>,
+    timeout: Option<Duration>,
+    delivery_guarantee: DeliveryGuarantee,
+    ack_mode: AckMode,
+    ack_batch: AckBatchConfig,
+    consumer_config: ConsumerConfig,
+    tag_prefix: String,
+    readiness: Option<Arc<watch::Sender<bool>>>,
+    handler_metrics: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -34,7 +84,13 @@ pub struct InputMessage {
     pub user_id: String,
     pub product_name: String,
     pub quantity: i32,
-    pub price: f64,
+    #[serde(with = "crate::money::json_number")]
+    pub price: Decimal,
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// Fields this version doesn't know about.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -43,7 +99,13 @@ pub struct OutputMessage {
     pub user_id: String,
     pub product_name: String,
     pub quantity: i32,
-    pub price: f64,
+    #[serde(with = "crate::money::json_number")]
+    pub price: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    /// Unknown input fields, re-emitted when passing them through.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Clone)]
@@ -51,111 +113,712 @@ pub struct QueueProcessor {
     pub consumer: AMQPConsumer,
     pub input_queue: String,
     pub output_queue: String,
+    transforms: Arc<TransformPipeline>,
+    script: Option<Arc<ScriptRunner>>,
+    money: Option<MoneyPolicy>,
+    unknown_fields: UnknownFields,
 }
 
 impl AMQPConsumer {
-    pub fn new(channel: Channel, publisher: AMQPPublisher, metrics: Arc<Metrics>, concurrency: usize) -> Self {
+    pub fn new(channel: Channel, output: OutputStage, metrics: Arc<Metrics>, concurrency: usize) -> Self {
         Self {
             channel: Arc::new(channel),
-            publisher,
+            output,
             metrics,
             concurrency,
+            partial_failure: PartialFailurePolicy::default(),
+            batch: None,
+            ordering: None,
+            adaptive_concurrency: None,
+            rate_limiter: None,
+            breaker: None,
+            blocked: None,
+            timeout: None,
+            delivery_guarantee: DeliveryGuarantee::default(),
+            ack_mode: AckMode::default(),
+            ack_batch: AckBatchConfig::default(),
+            consumer_config: ConsumerConfig::default(),
+            tag_prefix: format!("{}-default", local_instance_name()),
+            readiness: None,
+            handler_metrics: true,
         }
     }
 
+    /// What to do with a delivery when some of its outputs fail to publish.
+    pub fn with_partial_failure_policy(mut self, policy: PartialFailurePolicy) -> Self {
+        self.partial_failure = policy;
+        self
+    }
+
+    /// Collect outputs into batch messages and multi-ack their inputs.
+    pub fn with_batching(mut self, config: Option<BatchConfig>) -> Self {
+        self.batch = config;
+        self
+    }
+
+    /// Process messages sharing a key one at a time, in arrival order.
+    pub fn with_ordering(mut self, config: Option<OrderingConfig>) -> Self {
+        self.ordering = config;
+        self
+    }
+
+    /// Adjust concurrency from publish-confirm latency and errors.
+    pub fn with_adaptive_concurrency(mut self, config: Option<AdaptiveConcurrencyConfig>) -> Self {
+        self.adaptive_concurrency = config;
+        self
+    }
+
+    /// Hold deliveries until the consume rate limiter lets them through.
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Stop taking deliveries while the publish circuit breaker is open.
+    pub fn with_circuit_breaker(mut self, breaker: Option<Arc<CircuitBreaker>>) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    /// Stop taking deliveries while the connection is blocked by the broker.
+    pub fn with_blocked_signal(mut self, blocked: watch::Receiver<bool>) -> Self {
+        self.blocked = Some(blocked);
+        self
+    }
+
+    /// Give up on a message that takes longer than `timeout` to handle and
+    /// publish.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Choose when deliveries are acknowledged relative to publishing. The
+    /// transactional guarantee needs a channel from
+    /// `create_transactional_channel` that the publisher shares.
+    pub fn with_delivery_guarantee(mut self, guarantee: DeliveryGuarantee) -> Self {
+        self.delivery_guarantee = guarantee;
+        self
+    }
+
+    /// Choose between auto-ack, one ack per delivery, and batched acks.
+    pub fn with_ack_mode(mut self, mode: AckMode, batch: AckBatchConfig) -> Self {
+        self.ack_mode = mode;
+        self.ack_batch = batch;
+        self
+    }
+
+    /// Subscription options, and the instance and pipeline names that
+    /// consumer tags start with.
+    pub fn with_consumer_config(mut self, config: ConsumerConfig, pipeline: &str) -> Self {
+        let instance = config.instance.clone().unwrap_or_else(local_instance_name);
+        self.tag_prefix = format!("{}-{}", instance, pipeline);
+        self.consumer_config = config;
+        self
+    }
+
+    /// Report whether the consumer is subscribed, for readiness probes.
+    pub fn with_readiness(mut self, readiness: watch::Sender<bool>) -> Self {
+        self.readiness = Some(Arc::new(readiness));
+        self
+    }
+
+    /// Whether the handler stack includes the metrics layer, which counts
+    /// handler failures itself. Without it the consumer counts them.
+    pub fn with_handler_metrics(mut self, handler_metrics: bool) -> Self {
+        self.handler_metrics = handler_metrics;
+        self
+    }
+
     #[instrument(skip(self, handler))]
     pub async fn start_consuming<H>(&self, queue_name: &str, handler: H) -> Result<()>
     where
         H: MessageHandler + Clone + 'static,
     {
+        check_modes(
+            ConsumptionMode {
+                batch: self.batch.is_some(),
+                ordered: self.ordering.is_some(),
+                adaptive: self.adaptive_concurrency.is_some(),
+            },
+            self.ack_mode,
+            self.delivery_guarantee,
+        )?;
+
         // Update active consumers metric
         self.metrics.set_active_consumers(self.concurrency as f64);
 
-        // Declare queue
-        self.channel
-            .queue_declare(
-                queue_name,
-                QueueDeclareOptions {
-                    durable: true,
-                    ..Default::default()
-                },
-                FieldTable::default(),
+        let processor = DeliveryProcessor {
+            output: self.output.clone(),
+            metrics: self.metrics.clone(),
+            partial_failure: self.partial_failure,
+            timeout: self.timeout,
+            guarantee: self.delivery_guarantee,
+            handler_metrics: self.handler_metrics,
+            acks: Acknowledger::new(
+                self.ack_mode,
+                self.channel.clone(),
+                &self.ack_batch,
+                self.metrics.clone(),
+            ),
+            transactions: (self.delivery_guarantee == DeliveryGuarantee::Transactional)
+                .then(|| Arc::new(TransactionalChannel::new(self.channel.clone()))),
+        };
+
+        let dispatcher = self.ordering.as_ref().map(|ordering| {
+            OrderedDispatcher::spawn(
+                handler.clone(),
+                processor.clone(),
+                ordering,
+                self.concurrency,
+                self.metrics.clone(),
             )
-            .await?;
+        });
+
+        // Create semaphore to limit concurrency, or let the adaptive limiter
+        // find the limit from publish feedback
+        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        let limiter = self
+            .adaptive_concurrency
+            .as_ref()
+            .map(|config| AdaptiveLimiter::new(config, self.concurrency, self.metrics.clone()));
+
+        // The broker cancels the consumer with basic.cancel when the queue is
+        // deleted or its leader moves. The channel stays open, so subscribe
+        // again; only a closed channel ends consumption
+        let mut backoff = RESUBSCRIBE_BACKOFF;
+        let mut cancelled = false;
+        loop {
+            let consumer = match self.subscribe(queue_name, cancelled).await {
+                Ok(consumer) => consumer,
+                Err(e) if self.channel.status().connected() => {
+                    warn!(
+                        queue = queue_name,
+                        error = %e,
+                        retry_in_ms = backoff.as_millis(),
+                        "Failed to subscribe, retrying"
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RESUBSCRIBE_BACKOFF);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            backoff = RESUBSCRIBE_BACKOFF;
+
+            let mut consumer = std::pin::pin!(self.gate(consumer));
+            self.set_ready(true);
+
+            let result = if let Some(batch) = &self.batch {
+                BatchConsumer::new(
+                    handler.clone(),
+                    self.output.clone(),
+                    self.metrics.clone(),
+                    self.concurrency,
+                    batch.clone(),
+                    self.partial_failure,
+                )
+                .with_timeout(self.timeout)
+                .with_handler_metrics(self.handler_metrics)
+                .run(&mut consumer)
+                .await
+            } else if let Some(dispatcher) = &dispatcher {
+                while let Some(delivery) = consumer.next().await {
+                    match delivery {
+                        Ok(delivery) => {
+                            processor.received(&delivery).await;
+                            dispatcher.dispatch(delivery).await
+                        }
+                        Err(e) => {
+                            error!(error = %e, "Failed to consume message");
+                        }
+                    }
+                }
+                Ok(())
+            } else {
+                // Process messages
+                while let Some(delivery) = consumer.next().await {
+                    match delivery {
+                        Ok(delivery) => {
+                            processor.received(&delivery).await;
+                            let handler = handler.clone();
+                            let semaphore = semaphore.clone();
+                            let limiter = limiter.clone();
+                            let processor = processor.clone();
+
+                            tokio::spawn(async move {
+                                if let Some(limiter) = limiter {
+                                    let permit = limiter.acquire().await;
+                                    if let Some(sample) = processor.process(&handler, delivery).await {
+                                        permit.record(sample);
+                                    }
+                                } else {
+                                    let _permit = semaphore.acquire().await.unwrap();
+                                    processor.process(&handler, delivery).await;
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error!(error = %e, "Failed to consume message");
+                        }
+                    }
+                }
+                Ok(())
+            };
+
+            self.set_ready(false);
+            warn!("Consumer stream ended");
+            result?;
+
+            if !self.channel.status().connected() {
+                return Err(anyhow!("consumer channel closed"));
+            }
+
+            self.metrics.inc_consumer_cancellations();
+            cancelled = true;
+            warn!(
+                queue = queue_name,
+                retry_in_ms = backoff.as_millis(),
+                "Consumer cancelled by the broker, resubscribing"
+            );
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    /// Declare the input queue and start a consumer on it with a tag that
+    /// tells instances and pipelines apart. After a cancellation the queue
+    /// is only declared again if `redeclare_on_cancel` is set.
+    async fn subscribe(&self, queue_name: &str, cancelled: bool) -> Result<Consumer> {
+        let declare = !cancelled || self.consumer_config.redeclare_on_cancel;
+        if declare {
+            let mut queue_args = FieldTable::default();
+            if self.consumer_config.single_active_consumer {
+                queue_args.insert("x-single-active-consumer".into(), AMQPValue::Boolean(true));
+            }
+            let queue = self
+                .channel
+                .queue_declare(
+                    queue_name,
+                    QueueDeclareOptions {
+                        durable: true,
+                        ..Default::default()
+                    },
+                    queue_args,
+                )
+                .await
+                .map_err(|e| self.declare_error(queue_name, e))?;
+
+            // A cancel after a leader move leaves the queue in place, but
+            // after a delete this recreates it empty and without bindings
+            if cancelled {
+                warn!(
+                    queue = queue_name,
+                    messages = queue.message_count(),
+                    consumers = queue.consumer_count(),
+                    "Redeclared the input queue after the consumer was cancelled; \
+                     if it was deleted, it has been recreated"
+                );
+            }
+        }
 
-        // Create consumer
-        let mut consumer = self
+        let consumer_tag = format!("{}-{}", self.tag_prefix, Uuid::new_v4());
+        let mut consume_args = FieldTable::default();
+        if let Some(priority) = self.consumer_config.priority {
+            consume_args.insert("x-priority".into(), AMQPValue::LongInt(priority));
+        }
+        let consumer = self
             .channel
             .basic_consume(
                 queue_name,
-                "rust-consumer",
-                BasicConsumeOptions::default(),
-                FieldTable::default(),
+                &consumer_tag,
+                BasicConsumeOptions {
+                    no_local: self.consumer_config.no_local,
+                    no_ack: self.ack_mode == AckMode::Auto,
+                    exclusive: self.consumer_config.exclusive,
+                    nowait: false,
+                },
+                consume_args,
             )
-            .await?;
+            .await
+            .map_err(|e| match &e {
+                lapin::Error::ProtocolError(amqp)
+                    if !declare
+                        && matches!(amqp.kind(), AMQPErrorKind::Soft(AMQPSoftError::NOTFOUND)) =>
+                {
+                    anyhow!(
+                        "input queue {queue_name} no longer exists and \
+                         amqp.consumer.redeclare_on_cancel is off ({e})"
+                    )
+                }
+                _ => e.into(),
+            })?;
 
         info!(
             queue = queue_name,
+            consumer_tag = %consumer_tag,
             concurrency = self.concurrency,
             "Started consuming messages"
         );
 
-        // Create semaphore to limit concurrency
-        let semaphore = Arc::new(Semaphore::new(self.concurrency));
+        Ok(consumer)
+    }
 
-        // Process messages
-        while let Some(delivery) = consumer.next().await {
-            match delivery {
-                Ok(delivery) => {
-                    let handler = handler.clone();
-                    let semaphore = semaphore.clone();
-                    let metrics = self.metrics.clone();
-                    let request_id = Uuid::new_v4().to_string();
-
-                    tokio::spawn(async move {
-                        let _permit = semaphore.acquire().await.unwrap();
-
-                        let span = tracing::info_span!("message_processing", request_id = %request_id);
-                        let _guard = span.enter();
-
-                        // Increment received messages
-                        metrics.inc_messages_received();
-
-                        let start = std::time::Instant::now();
-
-                        let result = handler.handle(delivery).await;
-                        let duration = start.elapsed();
-                        metrics.observe_processing_duration(duration);
-                        
-                        match result {
-                            Ok(delivery) => {
-                                metrics.inc_messages_processed();
-                                
-                                if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
-                                    error!(error = %e, "Failed to acknowledge message");
-                                } else {
-                                    info!(
-                                        duration_ms = duration.as_millis(),
-                                        "Message processed successfully"
-                                    );
-                                }
-                            }
-                            Err(e) => {
-                                metrics.inc_messages_failed();
-                                error!(error = %e, "Message processing failed");
-                            }
-                        }
-                    });
+    /// Hold each delivery while the connection is blocked or the circuit
+    /// breaker is open, and until the consume rate limit lets it through.
+    fn gate(&self, consumer: Consumer) -> impl Stream<Item = lapin::Result<Delivery>> {
+        let blocked = self.blocked.clone();
+        let breaker = self.breaker.clone();
+        let rate_limiter = self.rate_limiter.clone();
+
+        consumer.then(move |delivery| {
+            let blocked = blocked.clone();
+            let breaker = breaker.clone();
+            let rate_limiter = rate_limiter.clone();
+            async move {
+                if let Some(mut blocked) = blocked {
+                    // Only errors if the watcher is gone, and then there is
+                    // nothing left to wait for
+                    let _ = blocked.wait_for(|blocked| !*blocked).await;
                 }
-                Err(e) => {
-                    error!(error = %e, "Failed to consume message");
+                if let Some(breaker) = &breaker {
+                    breaker.wait_ready().await;
                 }
+                if let (Some(limiter), Ok(delivery)) = (&rate_limiter, &delivery) {
+                    limiter.acquire(delivery.data.len()).await;
+                }
+                delivery
+            }
+        })
+    }
+
+    /// RabbitMQ answers a declaration whose arguments differ from the
+    /// existing queue with PRECONDITION_FAILED, which names neither side.
+    /// Spell out which setting has to match.
+    fn declare_error(&self, queue_name: &str, error: lapin::Error) -> anyhow::Error {
+        let mismatch = matches!(
+            &error,
+            lapin::Error::ProtocolError(e)
+                if matches!(e.kind(), AMQPErrorKind::Soft(AMQPSoftError::PRECONDITIONFAILED))
+        );
+        if !mismatch {
+            return error.into();
+        }
+        let (wanted, existing) = if self.consumer_config.single_active_consumer {
+            ("true", "without")
+        } else {
+            ("false", "with")
+        };
+        anyhow!(
+            "input queue {queue_name} already exists {existing} x-single-active-consumer, but \
+             amqp.consumer.single_active_consumer is {wanted}; change the setting or delete \
+             and recreate the queue ({error})"
+        )
+    }
+
+    fn set_ready(&self, ready: bool) {
+        if let Some(readiness) = &self.readiness {
+            readiness.send_replace(ready);
+        }
+    }
+}
+
+/// Handles one delivery end to end: run the handler, publish its outputs
+/// and settle the delivery, in the order the delivery guarantee calls for.
+#[derive(Clone)]
+pub(super) struct DeliveryProcessor {
+    output: OutputStage,
+    metrics: Arc<Metrics>,
+    partial_failure: PartialFailurePolicy,
+    timeout: Option<Duration>,
+    guarantee: DeliveryGuarantee,
+    handler_metrics: bool,
+    acks: Acknowledger,
+    transactions: Option<Arc<TransactionalChannel>>,
+}
+
+impl DeliveryProcessor {
+    /// Note a delivery as it comes off the consumer, before it waits for a
+    /// worker.
+    pub(super) async fn received(&self, delivery: &Delivery) {
+        self.acks.received(delivery).await;
+    }
+
+    /// Returns how publishing went, or `None` if the handler failed before
+    /// anything was published.
+    pub(super) async fn process<H: MessageHandler>(
+        &self,
+        handler: &H,
+        delivery: Delivery,
+    ) -> Option<PublishSample> {
+        let metrics = &self.metrics;
+
+        if self.guarantee == DeliveryGuarantee::AtMostOnce {
+            if let Err(e) = self.acks.ack(&delivery).await {
+                error!(error = %e, "Failed to acknowledge message, skipping it");
+                return None;
             }
+            crash_point("after_ack");
+        }
+
+        let start = std::time::Instant::now();
+        let deadline = self.timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+
+        let outputs = match within(deadline, handler.handle(&delivery)).await {
+            Some(Ok(outputs)) => outputs,
+            Some(Err(e)) => {
+                if !self.handler_metrics {
+                    metrics.inc_messages_failed();
+                }
+                error!(error = %e, "Message processing failed, rejecting it");
+                if let Err(e) = self.settle_failure(&delivery, None, PartialFailurePolicy::Reject).await {
+                    error!(error = %e, "Failed to settle message after processing failure");
+                }
+                return None;
+            }
+            None => {
+                self.timed_out(&delivery, "handle", start.elapsed(), None).await;
+                return None;
+            }
+        };
+        crash_point("after_handle");
+
+        // In transactional mode the publishes and the ack commit together,
+        // and no other delivery uses the channel until then
+        let transaction = match &self.transactions {
+            Some(transactions) => Some(transactions.begin().await),
+            None => None,
+        };
+
+        let headers = headers_to_json(&delivery.properties);
+        let publish_start = std::time::Instant::now();
+        let report = match within(deadline, self.output.publish_all(&outputs, &headers)).await {
+            Some(report) => report,
+            None => {
+                self.timed_out(&delivery, "publish", start.elapsed(), transaction).await;
+                return Some(PublishSample {
+                    latency: publish_start.elapsed(),
+                    success: false,
+                });
+            }
+        };
+        let sample = PublishSample {
+            latency: publish_start.elapsed(),
+            success: report.failed == 0,
+        };
+        let duration = start.elapsed();
+        crash_point("after_publish");
+
+        if report.failed > 0 {
+            metrics.inc_messages_failed();
+            error!(
+                published = report.published,
+                failed = report.failed,
+                error = %report.errors.join("; "),
+                policy = ?self.partial_failure,
+                guarantee = ?self.guarantee,
+                "Failed to publish message outputs"
+            );
+
+            if let Err(e) = self.settle_failure(&delivery, transaction, self.partial_failure).await {
+                error!(error = %e, "Failed to settle message after publish failure");
+            }
+            return Some(sample);
+        }
+
+        metrics.inc_messages_processed();
+
+        if let Err(e) = self.commit(&delivery, transaction).await {
+            error!(error = %e, "Failed to acknowledge message");
+        } else {
+            info!(
+                duration_ms = duration.as_millis(),
+                published = report.published,
+                filtered = report.filtered,
+                "Message processed successfully"
+            );
+        }
+
+        Some(sample)
+    }
+
+    /// Acknowledge a delivery whose outputs all published.
+    async fn commit(&self, delivery: &Delivery, transaction: Option<Transaction<'_>>) -> Result<()> {
+        match self.guarantee {
+            DeliveryGuarantee::AtMostOnce => {}
+            DeliveryGuarantee::AtLeastOnce | DeliveryGuarantee::Transactional => {
+                self.acks.ack(delivery).await?;
+            }
+        }
+
+        if let Some(transaction) = transaction {
+            crash_point("before_commit");
+            transaction.commit().await?;
         }
 
-        warn!("Consumer stream ended");
         Ok(())
     }
+
+    /// Settle a failed delivery according to `policy`: the partial failure
+    /// policy for failed or late outputs, `Reject` for handler errors, which
+    /// would only fail again on redelivery. In transactional mode whatever
+    /// did publish is rolled back first, so the policy applies to all or
+    /// nothing.
+    async fn settle_failure(
+        &self,
+        delivery: &Delivery,
+        transaction: Option<Transaction<'_>>,
+        policy: PartialFailurePolicy,
+    ) -> Result<()> {
+        match (self.guarantee, &self.transactions) {
+            // Already acked on receipt
+            (DeliveryGuarantee::AtMostOnce, _) => Ok(()),
+            (DeliveryGuarantee::Transactional, Some(transactions)) => {
+                let transaction = match transaction {
+                    Some(transaction) => transaction,
+                    None => transactions.begin().await,
+                };
+                transaction.rollback().await?;
+                self.acks.settle_failure(delivery, policy).await?;
+                transaction.commit().await
+            }
+            _ => self.acks.settle_failure(delivery, policy).await,
+        }
+    }
+
+    /// Settle a delivery that missed its deadline the same way as a failed
+    /// publish, so it is retried or dead-lettered by the same policy.
+    async fn timed_out(
+        &self,
+        delivery: &Delivery,
+        stage: &'static str,
+        elapsed: Duration,
+        transaction: Option<Transaction<'_>>,
+    ) {
+        self.metrics.inc_messages_failed();
+        self.metrics.inc_message_timeouts(stage);
+        warn!(
+            message_id = %message_id(delivery),
+            stage,
+            elapsed_ms = elapsed.as_millis(),
+            policy = ?self.partial_failure,
+            "Message processing timed out"
+        );
+
+        if let Err(e) = self.settle_failure(delivery, transaction, self.partial_failure).await {
+            error!(error = %e, "Failed to settle message after timeout");
+        }
+    }
+}
+
+/// Which optional consumption modes are configured.
+#[derive(Debug, Default, Clone, Copy)]
+struct ConsumptionMode {
+    batch: bool,
+    ordered: bool,
+    adaptive: bool,
+}
+
+/// Refuse combinations of modes, ack modes and delivery guarantees that
+/// can't keep their promises together.
+fn check_modes(mode: ConsumptionMode, ack_mode: AckMode, guarantee: DeliveryGuarantee) -> Result<()> {
+    if mode.batch && guarantee != DeliveryGuarantee::AtLeastOnce {
+        return Err(anyhow!("batch mode only supports the at_least_once delivery guarantee"));
+    }
+    if mode.batch && ack_mode != AckMode::Manual {
+        return Err(anyhow!("batch mode acknowledges its own batches and needs ack_mode manual"));
+    }
+    // Ordered and batch modes bound their own concurrency and don't report
+    // publish feedback to the limiter
+    if mode.adaptive && (mode.batch || mode.ordered) {
+        return Err(anyhow!(
+            "adaptive_concurrency only applies to per-message mode, not to ordering or batch"
+        ));
+    }
+    match (ack_mode, guarantee) {
+        (AckMode::Auto, DeliveryGuarantee::AtMostOnce) => {}
+        (AckMode::Auto, _) => {
+            return Err(anyhow!("ack_mode auto needs the at_most_once delivery guarantee"));
+        }
+        // A held-back ack would miss the commit it belongs to
+        (AckMode::Batched, DeliveryGuarantee::Transactional) => {
+            return Err(anyhow!("ack_mode batched doesn't support transactional delivery"));
+        }
+        // The ack on receipt would be held back past the publish, so a crash
+        // in between redelivers the message and duplicates its outputs
+        (AckMode::Batched, DeliveryGuarantee::AtMostOnce) => {
+            return Err(anyhow!(
+                "ack_mode batched doesn't support at_most_once delivery; use manual or auto"
+            ));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Run `future` until `deadline`, returning `None` if it expires first.
+pub(super) async fn within<F: Future>(
+    deadline: Option<tokio::time::Instant>,
+    future: F,
+) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Identifier to log for a delivery: its `message_id` property if the
+/// publisher set one, otherwise the delivery tag.
+pub(super) fn message_id(delivery: &Delivery) -> String {
+    match delivery.properties.message_id() {
+        Some(id) => id.to_string(),
+        None => format!("delivery-{}", delivery.delivery_tag),
+    }
+}
+
+/// Best guess at the name of this instance: the pod name under Kubernetes,
+/// otherwise the host name.
+pub(super) fn local_instance_name() -> String {
+    std::env::var("POD_NAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|name| name.trim().to_string())
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Acknowledge or reject a delivery whose outputs were only partly
+/// published, according to the configured policy. With `multiple`, every
+/// outstanding delivery up to this one is settled the same way.
+pub(super) async fn settle_partial_failure(
+    delivery: &Delivery,
+    policy: PartialFailurePolicy,
+    multiple: bool,
+) -> Result<()> {
+    match policy {
+        PartialFailurePolicy::Requeue => {
+            delivery
+                .nack(BasicNackOptions {
+                    multiple,
+                    requeue: true,
+                })
+                .await?
+        }
+        PartialFailurePolicy::Reject => {
+            delivery
+                .nack(BasicNackOptions {
+                    multiple,
+                    requeue: false,
+                })
+                .await?
+        }
+        PartialFailurePolicy::Ack => delivery.ack(BasicAckOptions { multiple }).await?,
+    }
+
+    Ok(())
 }
 
 impl QueueProcessor {
@@ -164,41 +827,180 @@ impl QueueProcessor {
             consumer,
             input_queue,
             output_queue,
+            transforms: Arc::new(TransformPipeline::default()),
+            script: None,
+            money: None,
+            unknown_fields: UnknownFields::default(),
         }
     }
+
+    /// Round and check `price` as an exact decimal on the typed path.
+    pub fn with_money(mut self, money: Option<MoneyPolicy>) -> Self {
+        self.money = money;
+        self
+    }
+
+    /// Choose whether fields `InputMessage` doesn't know are forwarded.
+    pub fn with_unknown_fields(mut self, unknown_fields: UnknownFields) -> Self {
+        self.unknown_fields = unknown_fields;
+        self
+    }
+
+    /// Replace the fixed `InputMessage` -> `OutputMessage` mapping with a
+    /// declarative transform pipeline applied to the raw JSON body.
+    pub fn with_transforms(mut self, transforms: TransformPipeline) -> Self {
+        self.transforms = Arc::new(transforms);
+        self
+    }
+
+    /// Run a script after the transforms. The script decides how many
+    /// messages to emit and where each one is routed.
+    pub fn with_script(mut self, script: Arc<ScriptRunner>) -> Self {
+        self.script = Some(script);
+        self
+    }
+
+    async fn transform(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let input: serde_json::Value = serde_json::from_slice(&delivery.data)?;
+        let messages = self.transforms.apply(input)?;
+
+        let outputs = match &self.script {
+            Some(script) => {
+                let headers = headers_to_json(&delivery.properties);
+                let mut outputs = Vec::new();
+                for message in messages {
+                    outputs.extend(self.run_script(script.clone(), message, headers.clone()).await?);
+                }
+                outputs
+            }
+            None => messages.into_iter().map(RoutedMessage::new).collect(),
+        };
+
+        info!(
+            input_queue = %self.input_queue,
+            outputs = outputs.len(),
+            "Message transformed"
+        );
+
+        Ok(outputs)
+    }
+
+    async fn run_script(
+        &self,
+        script: Arc<ScriptRunner>,
+        message: serde_json::Value,
+        headers: serde_json::Value,
+    ) -> Result<Vec<RoutedMessage>> {
+        let start = std::time::Instant::now();
+
+        let result = tokio::task::spawn_blocking(move || script.run(message, headers)).await?;
+        self.consumer
+            .metrics
+            .observe_script_duration(start.elapsed());
+
+        result
+    }
 }
 
 #[async_trait]
 impl MessageHandler for QueueProcessor {
     #[instrument(skip(self, delivery))]
-    async fn handle(&self, delivery: Delivery) -> Result<Delivery> {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        if !self.transforms.is_empty() || self.script.is_some() {
+            return self.transform(delivery).await;
+        }
+
         // Parse input message
         let input_msg: InputMessage = serde_json::from_slice(&delivery.data)?;
 
+        let (price, currency) = match &self.money {
+            Some(money) => (
+                money.normalize("price", input_msg.price)?,
+                money.currency(input_msg.currency.as_deref())?,
+            ),
+            None => (input_msg.price, input_msg.currency.clone()),
+        };
+
         // Create output message with UUID
         let output_msg = OutputMessage {
             id: Uuid::new_v4().to_string(),
             user_id: input_msg.user_id.clone(),
             product_name: input_msg.product_name.clone(),
             quantity: input_msg.quantity,
-            price: input_msg.price,
+            price,
+            currency,
+            extra: match self.unknown_fields {
+                UnknownFields::Drop => serde_json::Map::new(),
+                UnknownFields::PassThrough => {
+                    let mut extra = input_msg.extra;
+                    // The generated id always wins over one from the producer
+                    extra.remove("id");
+                    extra
+                }
+            },
         };
 
-        // Publish to output queue
-        self.consumer
-            .publisher
-            .publish(&self.output_queue, &output_msg)
-            .await?;
-
         info!(
             input_queue = %self.input_queue,
             output_queue = %self.output_queue,
             uuid_added = %output_msg.id,
             user_id = %output_msg.user_id,
             product_name = %output_msg.product_name,
-            "Message forwarded with UUID"
+            "Output message created with UUID"
         );
 
-        Ok(delivery)
+        Ok(vec![RoutedMessage::new(serde_json::to_value(&output_msg)?)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_adaptive_concurrency_outside_per_message_mode() {
+        let adaptive = ConsumptionMode {
+            adaptive: true,
+            ..Default::default()
+        };
+        let guarantee = DeliveryGuarantee::AtLeastOnce;
+
+        assert!(check_modes(adaptive, AckMode::Manual, guarantee).is_ok());
+        for mode in [
+            ConsumptionMode {
+                ordered: true,
+                ..adaptive
+            },
+            ConsumptionMode {
+                batch: true,
+                ..adaptive
+            },
+        ] {
+            let error = check_modes(mode, AckMode::Manual, guarantee).unwrap_err();
+            assert!(error.to_string().contains("adaptive_concurrency"), "{error}");
+        }
+    }
+
+    #[test]
+    fn refuses_batch_mode_without_manual_at_least_once() {
+        let batch = ConsumptionMode {
+            batch: true,
+            ..Default::default()
+        };
+
+        assert!(check_modes(batch, AckMode::Manual, DeliveryGuarantee::AtLeastOnce).is_ok());
+        assert!(check_modes(batch, AckMode::Batched, DeliveryGuarantee::AtLeastOnce).is_err());
+        assert!(check_modes(batch, AckMode::Manual, DeliveryGuarantee::Transactional).is_err());
+    }
+
+    #[test]
+    fn refuses_batched_acks_that_would_hold_back_an_ack_on_receipt() {
+        let mode = ConsumptionMode::default();
+
+        assert!(check_modes(mode, AckMode::Batched, DeliveryGuarantee::AtLeastOnce).is_ok());
+        assert!(check_modes(mode, AckMode::Batched, DeliveryGuarantee::AtMostOnce).is_err());
+        assert!(check_modes(mode, AckMode::Batched, DeliveryGuarantee::Transactional).is_err());
+        assert!(check_modes(mode, AckMode::Manual, DeliveryGuarantee::AtMostOnce).is_ok());
+        assert!(check_modes(mode, AckMode::Auto, DeliveryGuarantee::AtMostOnce).is_ok());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,38 @@
+//! Crash injection for the delivery guarantee integration tests. Setting
+//! `CRASH_AT` to one of the points below makes the process abort the first
+//! time it gets there, as a kill -9 would, so the tests can check what the
+//! broker redelivers and what was already published.
+//!
+//! - `after_handle`: the handler returned, nothing is published or acked
+//! - `after_ack`: at-most-once only, acked but nothing published
+//! - `after_publish`: outputs published, input not yet acked
+//! - `before_commit`: transactional only, published and acked but not
+//!   committed
+//!
+//! The hooks are only compiled in with the `crash-injection` feature, so a
+//! stray `CRASH_AT` in production can't take the processor down.
+
+#[cfg(feature = "crash-injection")]
+use std::sync::OnceLock;
+#[cfg(feature = "crash-injection")]
+use tracing::error;
+
+#[cfg(feature = "crash-injection")]
+pub const CRASH_AT_VAR: &str = "CRASH_AT";
+
+#[cfg(feature = "crash-injection")]
+static CRASH_AT: OnceLock<Option<String>> = OnceLock::new();
+
+#[cfg(not(feature = "crash-injection"))]
+#[inline(always)]
+pub(super) fn crash_point(_point: &str) {}
+
+#[cfg(feature = "crash-injection")]
+pub(super) fn crash_point(point: &str) {
+    let crash_at = CRASH_AT.get_or_init(|| std::env::var(CRASH_AT_VAR).ok());
+
+    if crash_at.as_deref() == Some(point) {
+        error!(point, "Crashing at injected crash point");
+        std::process::abort();
+    }
+}
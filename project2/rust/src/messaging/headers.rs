@@ -0,0 +1,80 @@
+use lapin::{
+    types::{AMQPValue, FieldArray, FieldTable, LongString},
+    BasicProperties,
+};
+use serde_json::{Map, Value};
+
+/// Convert the AMQP headers of a delivery into a JSON object so they can be
+/// handed to scripts and expressions alongside the message body.
+pub fn headers_to_json(properties: &BasicProperties) -> Value {
+    properties
+        .headers()
+        .as_ref()
+        .map(table_to_json)
+        .unwrap_or_else(|| Value::Object(Map::new()))
+}
+
+fn table_to_json(table: &FieldTable) -> Value {
+    Value::Object(
+        table
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value_to_json(value)))
+            .collect(),
+    )
+}
+
+fn value_to_json(value: &AMQPValue) -> Value {
+    match value {
+        AMQPValue::Boolean(b) => Value::Bool(*b),
+        AMQPValue::ShortShortInt(i) => Value::from(*i),
+        AMQPValue::ShortShortUInt(i) => Value::from(*i),
+        AMQPValue::ShortInt(i) => Value::from(*i),
+        AMQPValue::ShortUInt(i) => Value::from(*i),
+        AMQPValue::LongInt(i) => Value::from(*i),
+        AMQPValue::LongUInt(i) => Value::from(*i),
+        AMQPValue::LongLongInt(i) => Value::from(*i),
+        AMQPValue::Float(f) => Value::from(*f),
+        AMQPValue::Double(f) => Value::from(*f),
+        AMQPValue::DecimalValue(d) => {
+            Value::from(d.value as f64 / 10f64.powi(d.scale as i32))
+        }
+        AMQPValue::ShortString(s) => Value::String(s.to_string()),
+        AMQPValue::LongString(s) => Value::String(s.to_string()),
+        AMQPValue::FieldArray(items) => {
+            Value::Array(items.as_slice().iter().map(value_to_json).collect())
+        }
+        AMQPValue::Timestamp(t) => Value::from(*t),
+        AMQPValue::FieldTable(table) => table_to_json(table),
+        AMQPValue::ByteArray(bytes) => {
+            Value::String(String::from_utf8_lossy(bytes.as_slice()).into_owned())
+        }
+        AMQPValue::Void => Value::Null,
+    }
+}
+
+/// Convert a JSON object into AMQP headers for publishing. Integers become
+/// signed 64-bit values, other numbers doubles, and nested arrays and
+/// objects field arrays and tables.
+pub fn json_to_table(headers: &Map<String, Value>) -> FieldTable {
+    let mut table = FieldTable::default();
+    for (key, value) in headers {
+        table.insert(key.as_str().into(), json_to_value(value));
+    }
+    table
+}
+
+fn json_to_value(value: &Value) -> AMQPValue {
+    match value {
+        Value::Null => AMQPValue::Void,
+        Value::Bool(b) => AMQPValue::Boolean(*b),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => AMQPValue::LongLongInt(i),
+            None => AMQPValue::Double(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => AMQPValue::LongString(LongString::from(s.as_str())),
+        Value::Array(items) => AMQPValue::FieldArray(FieldArray::from(
+            items.iter().map(json_to_value).collect::<Vec<_>>(),
+        )),
+        Value::Object(map) => AMQPValue::FieldTable(json_to_table(map)),
+    }
+}
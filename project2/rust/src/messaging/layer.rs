@@ -0,0 +1,902 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lapin::message::Delivery;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use std::path::Path;
+use tracing::{debug, warn, Instrument};
+use uuid::Uuid;
+
+use super::cloudevents::CloudEventsLayer;
+use super::consumer::{local_instance_name, message_id, MessageHandler};
+use super::headers::headers_to_json;
+use super::output::RoutedMessage;
+use crate::config::{
+    EnrichConfig, EnrichField, EnrichTarget, LayerConfig, LookupConfig, LookupMissPolicy,
+    ValidationConfig,
+};
+use crate::lookup::{key_string, LookupTable};
+use crate::metrics::Metrics;
+use crate::validation::{FieldError, SchemaRegistry};
+
+/// Wraps a handler in another handler that adds some cross-cutting
+/// behaviour, in the spirit of tower's `Layer`. Handlers are type-erased so
+/// that the stack can be assembled from configuration.
+pub trait Layer: Send + Sync {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler>;
+}
+
+/// Ordered list of layers. The first layer is the outermost: it sees the
+/// delivery first and the result last.
+#[derive(Default)]
+pub struct HandlerStack {
+    layers: Vec<Box<dyn Layer>>,
+}
+
+impl HandlerStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a layer inside the ones already on the stack.
+    pub fn layer<L: Layer + 'static>(mut self, layer: L) -> Self {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    pub fn from_config(
+        configs: &[LayerConfig],
+        input_queue: &str,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        configs.iter().try_fold(Self::new(), |stack, config| {
+            Ok(match config {
+                LayerConfig::Logging => stack.layer(LoggingLayer),
+                LayerConfig::Metrics => stack.layer(MetricsLayer::new(metrics.clone())),
+                LayerConfig::Timeout { timeout_ms } => stack.layer(TimeoutLayer::new(
+                    Duration::from_millis(*timeout_ms),
+                    metrics.clone(),
+                )),
+                LayerConfig::Retry {
+                    max_attempts,
+                    backoff_ms,
+                } => stack.layer(RetryLayer::new(
+                    *max_attempts,
+                    Duration::from_millis(*backoff_ms),
+                    metrics.clone(),
+                )),
+                LayerConfig::Dedup { capacity, ttl_ms } => stack.layer(DedupLayer::new(
+                    *capacity,
+                    Duration::from_millis(*ttl_ms),
+                    metrics.clone(),
+                )),
+                LayerConfig::Validation(validation) => {
+                    stack.layer(ValidationLayer::from_config(validation, metrics.clone())?)
+                }
+                LayerConfig::Enrich(enrich) => {
+                    stack.layer(EnrichLayer::from_config(enrich, input_queue))
+                }
+                LayerConfig::Cloudevents(cloudevents) => {
+                    stack.layer(CloudEventsLayer::new(cloudevents))
+                }
+                LayerConfig::Lookup(lookup) => {
+                    stack.layer(LookupLayer::from_config(lookup, metrics.clone())?)
+                }
+            })
+        })
+    }
+
+    pub fn wrap(&self, handler: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        self.layers
+            .iter()
+            .rev()
+            .fold(handler, |inner, layer| layer.layer(inner))
+    }
+}
+
+/// Runs the inner handler in a `message_processing` span carrying a fresh
+/// request id, so every log line for one message can be correlated.
+pub struct LoggingLayer;
+
+impl Layer for LoggingLayer {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        Arc::new(Logged { inner })
+    }
+}
+
+struct Logged {
+    inner: Arc<dyn MessageHandler>,
+}
+
+#[async_trait]
+impl MessageHandler for Logged {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let span = tracing::info_span!(
+            "message_processing",
+            request_id = %Uuid::new_v4(),
+            message_id = %message_id(delivery),
+        );
+
+        async move {
+            let result = self.inner.handle(delivery).await;
+            match &result {
+                Ok(outputs) => debug!(outputs = outputs.len(), "Handler finished"),
+                Err(e) => debug!(error = %e, "Handler failed"),
+            }
+            result
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Counts received messages and handler failures and times the inner
+/// handler.
+pub struct MetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl Layer for MetricsLayer {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        Arc::new(Metered {
+            inner,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+struct Metered {
+    inner: Arc<dyn MessageHandler>,
+    metrics: Arc<Metrics>,
+}
+
+#[async_trait]
+impl MessageHandler for Metered {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        self.metrics.inc_messages_received();
+
+        let start = Instant::now();
+        let result = self.inner.handle(delivery).await;
+        self.metrics.observe_processing_duration(start.elapsed());
+
+        if result.is_err() {
+            self.metrics.inc_messages_failed();
+        }
+        result
+    }
+}
+
+/// Fails the inner handler if it runs longer than `timeout`. Unlike
+/// `message_timeout_ms`, which covers publishing and settles the delivery,
+/// this only bounds the layers inside it.
+pub struct TimeoutLayer {
+    timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl TimeoutLayer {
+    pub fn new(timeout: Duration, metrics: Arc<Metrics>) -> Self {
+        Self { timeout, metrics }
+    }
+}
+
+impl Layer for TimeoutLayer {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        Arc::new(TimeLimited {
+            inner,
+            timeout: self.timeout,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+struct TimeLimited {
+    inner: Arc<dyn MessageHandler>,
+    timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+#[async_trait]
+impl MessageHandler for TimeLimited {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        match tokio::time::timeout(self.timeout, self.inner.handle(delivery)).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.metrics.inc_message_timeouts("handle");
+                warn!(
+                    message_id = %message_id(delivery),
+                    stage = "handle",
+                    timeout_ms = self.timeout.as_millis(),
+                    "Message processing timed out"
+                );
+                Err(anyhow!("handler timed out after {:?}", self.timeout))
+            }
+        }
+    }
+}
+
+/// Retries the inner handler on error, doubling the backoff after each
+/// attempt. Only the handler is retried; publishing is not.
+pub struct RetryLayer {
+    max_attempts: u32,
+    backoff: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl RetryLayer {
+    pub fn new(max_attempts: u32, backoff: Duration, metrics: Arc<Metrics>) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff,
+            metrics,
+        }
+    }
+}
+
+impl Layer for RetryLayer {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        Arc::new(Retrying {
+            inner,
+            max_attempts: self.max_attempts,
+            backoff: self.backoff,
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+struct Retrying {
+    inner: Arc<dyn MessageHandler>,
+    max_attempts: u32,
+    backoff: Duration,
+    metrics: Arc<Metrics>,
+}
+
+#[async_trait]
+impl MessageHandler for Retrying {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+
+        loop {
+            match self.inner.handle(delivery).await {
+                Ok(outputs) => return Ok(outputs),
+                Err(e) if attempt < self.max_attempts => {
+                    warn!(
+                        message_id = %message_id(delivery),
+                        attempt,
+                        max_attempts = self.max_attempts,
+                        error = %e,
+                        "Handler failed, retrying"
+                    );
+                    self.metrics.inc_handler_retries();
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Drops deliveries whose AMQP `message_id` the inner handler already
+/// handled successfully, returning no outputs so they are acknowledged.
+/// Ids are kept in memory, bounded by `capacity` and `ttl`.
+///
+/// Redelivered messages are always handled again: the broker only
+/// redelivers a message this processor failed to settle, for instance after
+/// its outputs failed to publish. Messages without a `message_id` pass
+/// through.
+pub struct DedupLayer {
+    seen: Arc<Mutex<SeenIds>>,
+    metrics: Arc<Metrics>,
+}
+
+impl DedupLayer {
+    pub fn new(capacity: usize, ttl: Duration, metrics: Arc<Metrics>) -> Self {
+        Self {
+            seen: Arc::new(Mutex::new(SeenIds::new(capacity, ttl))),
+            metrics,
+        }
+    }
+}
+
+impl Layer for DedupLayer {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        Arc::new(Deduplicated {
+            inner,
+            seen: self.seen.clone(),
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+struct Deduplicated {
+    inner: Arc<dyn MessageHandler>,
+    seen: Arc<Mutex<SeenIds>>,
+    metrics: Arc<Metrics>,
+}
+
+#[async_trait]
+impl MessageHandler for Deduplicated {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let Some(id) = delivery.properties.message_id().as_ref().map(|id| id.to_string()) else {
+            return self.inner.handle(delivery).await;
+        };
+
+        if !delivery.redelivered && self.seen.lock().unwrap().contains(&id, Instant::now()) {
+            self.metrics.inc_duplicates_dropped();
+            debug!(message_id = %id, "Dropping duplicate message");
+            return Ok(Vec::new());
+        }
+
+        // Only remember ids that were handled, so a failed message can be
+        // retried
+        let outputs = self.inner.handle(delivery).await?;
+        self.seen.lock().unwrap().insert(id, Instant::now());
+        Ok(outputs)
+    }
+}
+
+/// Message ids in insertion order, each remembered until `ttl` passes or
+/// `capacity` newer ids push it out.
+struct SeenIds {
+    capacity: usize,
+    ttl: Duration,
+    /// Latest insertion of each id, by sequence number.
+    ids: HashMap<String, u64>,
+    order: VecDeque<(String, u64, Instant)>,
+    next: u64,
+}
+
+impl SeenIds {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ttl,
+            ids: HashMap::new(),
+            order: VecDeque::new(),
+            next: 0,
+        }
+    }
+
+    fn contains(&mut self, id: &str, now: Instant) -> bool {
+        self.expire(now);
+        self.ids.contains_key(id)
+    }
+
+    fn insert(&mut self, id: String, now: Instant) {
+        self.expire(now);
+        // A newer entry supersedes any queued one for the same id
+        self.next += 1;
+        self.ids.insert(id.clone(), self.next);
+        self.order.push_back((id, self.next, now));
+
+        while self.ids.len() > self.capacity {
+            self.pop_oldest();
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self
+            .order
+            .front()
+            .is_some_and(|(_, _, at)| now.duration_since(*at) >= self.ttl)
+        {
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((id, seq, _)) = self.order.pop_front() {
+            if self.ids.get(&id) == Some(&seq) {
+                self.ids.remove(&id);
+            }
+        }
+    }
+}
+
+/// Validates message bodies against the JSON Schema named by a version
+/// header. Valid messages continue to the inner handler; invalid ones are
+/// turned into a single output for the rejection queue, so the delivery is
+/// acknowledged once the rejection is confirmed.
+pub struct ValidationLayer {
+    schemas: Arc<SchemaRegistry>,
+    config: Arc<ValidationConfig>,
+    metrics: Arc<Metrics>,
+}
+
+impl ValidationLayer {
+    pub fn from_config(config: &ValidationConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        Ok(Self {
+            schemas: Arc::new(SchemaRegistry::load(Path::new(&config.schema_dir))?),
+            config: Arc::new(config.clone()),
+            metrics,
+        })
+    }
+}
+
+impl Layer for ValidationLayer {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        Arc::new(Validated {
+            inner,
+            schemas: self.schemas.clone(),
+            config: self.config.clone(),
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+struct Validated {
+    inner: Arc<dyn MessageHandler>,
+    schemas: Arc<SchemaRegistry>,
+    config: Arc<ValidationConfig>,
+    metrics: Arc<Metrics>,
+}
+
+#[async_trait]
+impl MessageHandler for Validated {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let headers = headers_to_json(&delivery.properties);
+        let version = match headers.get(&self.config.version_header) {
+            Some(Value::String(version)) => Some(version.clone()),
+            Some(Value::Number(version)) => Some(version.to_string()),
+            _ => self.config.default_version.clone(),
+        };
+
+        let errors = match &version {
+            Some(version) => self.schemas.validate(version, &delivery.data),
+            None => vec![FieldError::whole_message(format!(
+                "missing '{}' header",
+                self.config.version_header
+            ))],
+        };
+
+        if errors.is_empty() {
+            return self.inner.handle(delivery).await;
+        }
+
+        // Labels come from config and schemas only, never from the message
+        let version_label = match version.as_deref() {
+            Some(version) if self.schemas.knows(version) => version,
+            Some(_) => "unknown",
+            None => "none",
+        };
+        for error in &errors {
+            self.metrics.inc_schema_violations(version_label, &error.rule);
+        }
+        warn!(
+            message_id = %message_id(delivery),
+            schema_version = version_label,
+            errors = ?errors,
+            rejection_queue = %self.config.rejection_queue,
+            "Message failed schema validation"
+        );
+
+        let original = serde_json::from_slice::<Value>(&delivery.data).unwrap_or_else(|_| {
+            Value::String(String::from_utf8_lossy(&delivery.data).into_owned())
+        });
+
+        Ok(vec![RoutedMessage {
+            exchange: Some(String::new()),
+            routing_key: Some(self.config.rejection_queue.clone()),
+            headers: Default::default(),
+            content_type: None,
+            body: json!({
+                "reason": "schema_validation",
+                "schema_version": version,
+                "errors": errors,
+                "message": original,
+            }),
+            rejected: true,
+        }])
+    }
+}
+
+/// Adds processing metadata to every output of the inner handler, either
+/// as body fields or as AMQP headers. Non-object bodies are left alone in
+/// body mode.
+pub struct EnrichLayer {
+    config: Arc<EnrichConfig>,
+    source_queue: Arc<str>,
+    instance: Arc<str>,
+}
+
+impl EnrichLayer {
+    pub fn from_config(config: &EnrichConfig, source_queue: &str) -> Self {
+        let instance = config.instance.clone().unwrap_or_else(local_instance_name);
+
+        Self {
+            config: Arc::new(config.clone()),
+            source_queue: source_queue.into(),
+            instance: instance.into(),
+        }
+    }
+}
+
+impl Layer for EnrichLayer {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        Arc::new(Enriched {
+            inner,
+            config: self.config.clone(),
+            source_queue: self.source_queue.clone(),
+            instance: self.instance.clone(),
+        })
+    }
+}
+
+struct Enriched {
+    inner: Arc<dyn MessageHandler>,
+    config: Arc<EnrichConfig>,
+    source_queue: Arc<str>,
+    instance: Arc<str>,
+}
+
+#[async_trait]
+impl MessageHandler for Enriched {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let start = Instant::now();
+        let mut outputs = self.inner.handle(delivery).await?;
+        let latency_ms = start.elapsed().as_millis() as u64;
+        let processed_at =
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+        let metadata: Vec<(String, Value)> = self
+            .config
+            .fields
+            .iter()
+            .map(|field| {
+                let (name, value) = match field {
+                    EnrichField::ProcessedAt => ("processed_at", json!(processed_at)),
+                    EnrichField::SourceQueue => ("source_queue", json!(&*self.source_queue)),
+                    EnrichField::ProcessorInstance => {
+                        ("processor_instance", json!(&*self.instance))
+                    }
+                    EnrichField::ProcessorVersion => {
+                        ("processor_version", json!(env!("CARGO_PKG_VERSION")))
+                    }
+                    EnrichField::Redelivered => ("redelivered", json!(delivery.redelivered)),
+                    EnrichField::LatencyMs => ("latency_ms", json!(latency_ms)),
+                };
+                (format!("{}{}", self.config.prefix, name), value)
+            })
+            .collect();
+
+        for output in &mut outputs {
+            let target = match self.config.target {
+                EnrichTarget::Headers => &mut output.headers,
+                EnrichTarget::Body => match output.body.as_object_mut() {
+                    Some(body) => body,
+                    None => continue,
+                },
+            };
+            for (name, value) in &metadata {
+                target.insert(name.clone(), value.clone());
+            }
+        }
+
+        Ok(outputs)
+    }
+}
+
+/// Joins every output of the inner handler against a reference table,
+/// copying the matching row's fields onto object bodies. Outputs without a
+/// match are handled according to `on_miss`. Rejections from an inner
+/// validation layer and dead letters from an inner lookup are left alone.
+pub struct LookupLayer {
+    table: Arc<LookupTable>,
+    config: Arc<LookupConfig>,
+    metrics: Arc<Metrics>,
+}
+
+impl LookupLayer {
+    pub fn from_config(config: &LookupConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        if config.on_miss == LookupMissPolicy::DeadLetter && config.dead_letter_queue.is_none() {
+            return Err(anyhow!("lookup `on_miss: dead_letter` needs a `dead_letter_queue`"));
+        }
+
+        Ok(Self {
+            table: LookupTable::load(config)?,
+            config: Arc::new(config.clone()),
+            metrics,
+        })
+    }
+}
+
+impl Layer for LookupLayer {
+    fn layer(&self, inner: Arc<dyn MessageHandler>) -> Arc<dyn MessageHandler> {
+        Arc::new(LookedUp {
+            inner,
+            table: self.table.clone(),
+            config: self.config.clone(),
+            metrics: self.metrics.clone(),
+        })
+    }
+}
+
+struct LookedUp {
+    inner: Arc<dyn MessageHandler>,
+    table: Arc<LookupTable>,
+    config: Arc<LookupConfig>,
+    metrics: Arc<Metrics>,
+}
+
+#[async_trait]
+impl MessageHandler for LookedUp {
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let outputs = self.inner.handle(delivery).await?;
+        let rows = self.table.rows();
+        let table = self.table.name();
+
+        let mut joined = Vec::with_capacity(outputs.len());
+        for mut output in outputs {
+            if output.rejected {
+                joined.push(output);
+                continue;
+            }
+
+            let key = self
+                .config
+                .key
+                .split('.')
+                .try_fold(&output.body, |current, field| current.get(field))
+                .and_then(key_string);
+
+            let row = key.as_ref().and_then(|key| rows.get(key));
+            if let (Some(row), Some(body)) = (row, output.body.as_object_mut()) {
+                self.metrics.inc_lookup_hits(table);
+                for (column, value) in row {
+                    let wanted = match self.config.fields.is_empty() {
+                        true => column != self.table.key_column(),
+                        false => self.config.fields.contains(column),
+                    };
+                    if wanted {
+                        body.insert(format!("{}{}", self.config.prefix, column), value.clone());
+                    }
+                }
+                joined.push(output);
+                continue;
+            }
+
+            self.metrics.inc_lookup_misses(table);
+            debug!(
+                message_id = %message_id(delivery),
+                table,
+                key = ?key,
+                on_miss = ?self.config.on_miss,
+                "Lookup miss"
+            );
+
+            match self.config.on_miss {
+                LookupMissPolicy::Pass => joined.push(output),
+                LookupMissPolicy::Drop => {}
+                LookupMissPolicy::DeadLetter => joined.push(RoutedMessage {
+                    exchange: Some(String::new()),
+                    routing_key: self.config.dead_letter_queue.clone(),
+                    headers: output.headers,
+                    content_type: None,
+                    body: json!({
+                        "reason": "lookup_miss",
+                        "table": table,
+                        "key": key,
+                        "message": output.body,
+                    }),
+                    rejected: true,
+                }),
+            }
+        }
+
+        Ok(joined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::types::{AMQPValue, FieldTable};
+    use lapin::{acker::Acker, BasicProperties};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counting(AtomicUsize);
+
+    #[async_trait]
+    impl MessageHandler for Counting {
+        async fn handle(&self, _delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![RoutedMessage::new(json!({}))])
+        }
+    }
+
+    fn delivery(message_id: Option<&str>, redelivered: bool) -> Delivery {
+        let mut properties = BasicProperties::default();
+        if let Some(id) = message_id {
+            properties = properties.with_message_id(id.into());
+        }
+        Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "input".into(),
+            redelivered,
+            properties,
+            data: b"{}".to_vec(),
+            acker: Acker::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_duplicates_but_not_redeliveries() {
+        let inner = Arc::new(Counting(AtomicUsize::new(0)));
+        let handler = DedupLayer::new(10, Duration::from_secs(60), Arc::new(Metrics::new()))
+            .layer(inner.clone());
+
+        assert_eq!(handler.handle(&delivery(Some("a"), false)).await.unwrap().len(), 1);
+        assert!(handler.handle(&delivery(Some("a"), false)).await.unwrap().is_empty());
+        assert_eq!(handler.handle(&delivery(Some("a"), true)).await.unwrap().len(), 1);
+        assert_eq!(handler.handle(&delivery(None, false)).await.unwrap().len(), 1);
+        assert_eq!(handler.handle(&delivery(None, false)).await.unwrap().len(), 1);
+
+        assert_eq!(inner.0.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn forgets_ids_after_ttl() {
+        let mut seen = SeenIds::new(10, Duration::from_secs(60));
+        let start = Instant::now();
+
+        seen.insert("a".to_string(), start);
+
+        assert!(seen.contains("a", start + Duration::from_secs(59)));
+        assert!(!seen.contains("a", start + Duration::from_secs(60)));
+        assert!(seen.order.is_empty());
+    }
+
+    #[test]
+    fn forgets_oldest_ids_beyond_capacity() {
+        let mut seen = SeenIds::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        seen.insert("a".to_string(), now);
+        seen.insert("b".to_string(), now);
+        seen.insert("a".to_string(), now);
+        seen.insert("c".to_string(), now);
+
+        assert!(!seen.contains("b", now));
+        assert!(seen.contains("a", now));
+        assert!(seen.contains("c", now));
+    }
+
+    struct Outputs(fn() -> Vec<RoutedMessage>);
+
+    #[async_trait]
+    impl MessageHandler for Outputs {
+        async fn handle(&self, _delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+            Ok((self.0)())
+        }
+    }
+
+    fn lookup(
+        outputs: fn() -> Vec<RoutedMessage>,
+    ) -> (Arc<dyn MessageHandler>, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("lookup_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("products.csv");
+        std::fs::write(&path, "product_name,sku\nWidget,W-100\n").unwrap();
+
+        let config: LookupConfig = serde_json::from_value(json!({
+            "path": path,
+            "key": "product_name",
+            "on_miss": "dead_letter",
+            "dead_letter_queue": "misses",
+        }))
+        .unwrap();
+        let layer = LookupLayer::from_config(&config, Arc::new(Metrics::new())).unwrap();
+        (layer.layer(Arc::new(Outputs(outputs))), dir)
+    }
+
+    #[tokio::test]
+    async fn joins_outputs_and_dead_letters_misses_with_headers() {
+        let (handler, dir) = lookup(|| {
+            let mut miss = RoutedMessage::new(json!({"product_name": "Gizmo"}));
+            miss.headers.insert("tenant".to_string(), json!("acme"));
+            vec![RoutedMessage::new(json!({"product_name": "Widget"})), miss]
+        });
+
+        let outputs = handler.handle(&delivery(None, false)).await.unwrap();
+
+        assert_eq!(outputs[0].body, json!({"product_name": "Widget", "sku": "W-100"}));
+        assert_eq!(outputs[1].routing_key.as_deref(), Some("misses"));
+        assert_eq!(outputs[1].body["reason"], "lookup_miss");
+        assert_eq!(outputs[1].headers["tenant"], "acme");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaves_rejections_alone() {
+        let (handler, dir) = lookup(|| {
+            let mut rejection = RoutedMessage::new(json!({"reason": "schema_validation"}));
+            rejection.exchange = Some(String::new());
+            rejection.routing_key = Some("rejections".to_string());
+            rejection.rejected = true;
+            vec![rejection]
+        });
+
+        let outputs = handler.handle(&delivery(None, false)).await.unwrap();
+
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].routing_key.as_deref(), Some("rejections"));
+        assert_eq!(outputs[0].body, json!({"reason": "schema_validation"}));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn joins_script_outputs_on_the_default_exchange() {
+        // What a script or wasm handler returns for `routing_key: "orders"`
+        // once its exchange defaults to ""
+        let (handler, dir) = lookup(|| {
+            let output: RoutedMessage = serde_json::from_value(json!({
+                "exchange": "",
+                "routing_key": "orders",
+                "body": {"product_name": "Widget"},
+                "rejected": true,
+            }))
+            .unwrap();
+            vec![output]
+        });
+
+        let outputs = handler.handle(&delivery(None, false)).await.unwrap();
+
+        assert_eq!(outputs[0].routing_key.as_deref(), Some("orders"));
+        assert_eq!(outputs[0].body, json!({"product_name": "Widget", "sku": "W-100"}));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn labels_schema_violations_without_message_content() {
+        let dir = std::env::temp_dir().join(format!("schemas_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let schema = json!({"type": "object", "additionalProperties": false});
+        std::fs::write(dir.join("1.json"), schema.to_string()).unwrap();
+        let config = ValidationConfig {
+            schema_dir: dir.to_string_lossy().into_owned(),
+            version_header: "schema_version".to_string(),
+            default_version: None,
+            rejection_queue: "rejected".to_string(),
+        };
+        let metrics = Arc::new(Metrics::new());
+        let handler = ValidationLayer::from_config(&config, metrics.clone())
+            .unwrap()
+            .layer(Arc::new(Counting(AtomicUsize::new(0))));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let versioned = |version: &str, body: Value| {
+            let mut headers = FieldTable::default();
+            headers.insert("schema_version".into(), AMQPValue::LongString(version.into()));
+            Delivery {
+                properties: BasicProperties::default().with_headers(headers),
+                data: body.to_string().into_bytes(),
+                ..delivery(None, false)
+            }
+        };
+        handler.handle(&versioned("made-up-7", json!({}))).await.unwrap();
+        handler
+            .handle(&versioned("1", json!({"made_up_7": 1, "made_up_8": 2})))
+            .await
+            .unwrap();
+
+        let count = |labels: &[&str]| metrics.schema_violations.with_label_values(labels).get();
+        assert_eq!(count(&["unknown", "$"]), 1);
+        assert_eq!(count(&["1", "/additionalProperties"]), 1);
+        let series = prometheus::core::Collector::collect(&metrics.schema_violations)[0]
+            .get_metric()
+            .len();
+        assert_eq!(series, 2);
+    }
+}
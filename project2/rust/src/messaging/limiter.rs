@@ -0,0 +1,254 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use crate::config::AdaptiveConcurrencyConfig;
+use crate::metrics::Metrics;
+
+/// Outcome of publishing one delivery's outputs, fed back to the limiter.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct PublishSample {
+    pub latency: Duration,
+    pub success: bool,
+}
+
+struct LimiterState {
+    limit: usize,
+    in_flight: usize,
+    /// Permits to retire as they come back, after a decrease that found
+    /// fewer idle permits than it needed.
+    excess: usize,
+    /// Healthy samples since the limit last grew.
+    successes: usize,
+    last_decrease: Option<Instant>,
+}
+
+/// AIMD concurrency limiter. The limit grows by one after a full window of
+/// healthy publishes while the consumer is actually using most of it, and
+/// is cut by `backoff_ratio` when a publish fails or its confirm exceeds
+/// the latency threshold.
+pub(super) struct AdaptiveLimiter {
+    semaphore: Arc<Semaphore>,
+    state: Mutex<LimiterState>,
+    config: AdaptiveConcurrencyConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl AdaptiveLimiter {
+    pub(super) fn new(
+        config: &AdaptiveConcurrencyConfig,
+        default_limit: usize,
+        metrics: Arc<Metrics>,
+    ) -> Arc<Self> {
+        let min = config.min_limit.max(1);
+        let max = config.max_limit.max(min);
+        let limit = config.initial_limit.unwrap_or(default_limit).clamp(min, max);
+        metrics.set_concurrency_limit(limit);
+
+        Arc::new(Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            state: Mutex::new(LimiterState {
+                limit,
+                in_flight: 0,
+                excess: 0,
+                successes: 0,
+                last_decrease: None,
+            }),
+            config: AdaptiveConcurrencyConfig {
+                min_limit: min,
+                max_limit: max,
+                ..config.clone()
+            },
+            metrics,
+        })
+    }
+
+    pub(super) async fn acquire(self: &Arc<Self>) -> LimiterPermit {
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("limiter semaphore is never closed");
+        self.state.lock().unwrap().in_flight += 1;
+
+        LimiterPermit {
+            limiter: self.clone(),
+            permit: Some(permit),
+        }
+    }
+
+    fn record(&self, sample: PublishSample) {
+        let threshold = Duration::from_millis(self.config.latency_threshold_ms);
+        let mut state = self.state.lock().unwrap();
+
+        if !sample.success || sample.latency > threshold {
+            // Deliveries already in flight report the same congestion; only
+            // back off once per threshold interval so one slow spell doesn't
+            // collapse the limit to the minimum.
+            if state
+                .last_decrease
+                .is_some_and(|at| at.elapsed() < threshold)
+            {
+                return;
+            }
+
+            let reduced = ((state.limit as f64) * self.config.backoff_ratio).floor() as usize;
+            let target = reduced.max(self.config.min_limit);
+            if target < state.limit {
+                let mut to_retire = state.limit - target;
+                while to_retire > 0 {
+                    match self.semaphore.try_acquire() {
+                        Ok(permit) => permit.forget(),
+                        Err(_) => break,
+                    }
+                    to_retire -= 1;
+                }
+                state.excess += to_retire;
+                state.limit = target;
+            }
+            state.successes = 0;
+            state.last_decrease = Some(Instant::now());
+        } else {
+            state.successes += 1;
+            // Only grow while the current limit is the bottleneck.
+            let saturated = state.in_flight * 2 >= state.limit;
+            if state.successes >= state.limit && saturated && state.limit < self.config.max_limit {
+                if state.excess > 0 {
+                    state.excess -= 1;
+                } else {
+                    self.semaphore.add_permits(1);
+                }
+                state.limit += 1;
+                state.successes = 0;
+            } else {
+                return;
+            }
+        }
+
+        debug!(
+            limit = state.limit,
+            latency_ms = sample.latency.as_millis(),
+            success = sample.success,
+            "Concurrency limit changed"
+        );
+        self.metrics.set_concurrency_limit(state.limit);
+    }
+}
+
+/// A slot in the adaptive limit. Returned to the limiter on drop, or
+/// retired if the limit shrank while it was held.
+pub(super) struct LimiterPermit {
+    limiter: Arc<AdaptiveLimiter>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl LimiterPermit {
+    pub(super) fn record(&self, sample: PublishSample) {
+        self.limiter.record(sample);
+    }
+}
+
+impl Drop for LimiterPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.in_flight -= 1;
+        if let Some(permit) = self.permit.take() {
+            if state.excess > 0 {
+                state.excess -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEALTHY: PublishSample = PublishSample {
+        latency: Duration::from_millis(1),
+        success: true,
+    };
+    const FAILED: PublishSample = PublishSample {
+        latency: Duration::from_millis(1),
+        success: false,
+    };
+
+    fn limiter(initial: usize) -> Arc<AdaptiveLimiter> {
+        let config = AdaptiveConcurrencyConfig {
+            min_limit: 1,
+            max_limit: 8,
+            initial_limit: Some(initial),
+            latency_threshold_ms: 60_000,
+            backoff_ratio: 0.5,
+        };
+        AdaptiveLimiter::new(&config, 4, Arc::new(Metrics::new()))
+    }
+
+    fn limit(limiter: &AdaptiveLimiter) -> usize {
+        limiter.state.lock().unwrap().limit
+    }
+
+    #[tokio::test]
+    async fn grows_by_one_after_a_window_of_saturated_successes() {
+        let limiter = limiter(4);
+        let permits = [limiter.acquire().await, limiter.acquire().await];
+
+        for _ in 0..3 {
+            permits[0].record(HEALTHY);
+        }
+        assert_eq!(limit(&limiter), 4);
+        permits[0].record(HEALTHY);
+
+        assert_eq!(limit(&limiter), 5);
+        assert_eq!(limiter.semaphore.available_permits(), 3);
+    }
+
+    #[tokio::test]
+    async fn does_not_grow_while_mostly_idle() {
+        let limiter = limiter(4);
+        let permit = limiter.acquire().await;
+
+        for _ in 0..20 {
+            permit.record(HEALTHY);
+        }
+
+        assert_eq!(limit(&limiter), 4);
+    }
+
+    #[tokio::test]
+    async fn backs_off_once_per_threshold() {
+        let limiter = limiter(8);
+        let permit = limiter.acquire().await;
+
+        permit.record(FAILED);
+        permit.record(FAILED);
+        permit.record(PublishSample {
+            latency: Duration::from_secs(120),
+            success: true,
+        });
+
+        assert_eq!(limit(&limiter), 4);
+    }
+
+    #[tokio::test]
+    async fn retires_held_permits_when_they_return() {
+        let limiter = limiter(4);
+        let permits = vec![
+            limiter.acquire().await,
+            limiter.acquire().await,
+            limiter.acquire().await,
+            limiter.acquire().await,
+        ];
+
+        permits[0].record(FAILED);
+        assert_eq!(limit(&limiter), 2);
+        assert_eq!(limiter.semaphore.available_permits(), 0);
+
+        drop(permits);
+        assert_eq!(limiter.semaphore.available_permits(), 2);
+        assert_eq!(limiter.state.lock().unwrap().excess, 0);
+    }
+}
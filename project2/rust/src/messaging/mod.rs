@@ -1,5 +1,17 @@
+pub mod ack;
+pub mod batch;
+pub mod breaker;
+pub mod cloudevents;
 pub mod consumer;
+pub mod crash;
+pub mod headers;
+pub mod layer;
+pub mod limiter;
+pub mod ordered;
+pub mod output;
 pub mod publisher;
+pub mod transaction;
 
 pub use consumer::{AMQPConsumer, QueueProcessor};
+pub use output::{OutputStage, RoutedMessage};
 pub use publisher::AMQPPublisher;
\ No newline at end of file
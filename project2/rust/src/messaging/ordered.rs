@@ -0,0 +1,136 @@
+use lapin::message::Delivery;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{error, warn, Instrument};
+
+use super::consumer::{DeliveryProcessor, MessageHandler};
+use crate::config::OrderingConfig;
+use crate::metrics::Metrics;
+
+/// Spreads deliveries over a fixed set of sequential workers by key. Every
+/// delivery with the same key lands on the same worker and is processed in
+/// arrival order, while different keys proceed in parallel.
+pub(super) struct OrderedDispatcher {
+    key: String,
+    workers: Vec<mpsc::Sender<Delivery>>,
+    metrics: Arc<Metrics>,
+}
+
+impl OrderedDispatcher {
+    pub(super) fn spawn<H>(
+        handler: H,
+        processor: DeliveryProcessor,
+        config: &OrderingConfig,
+        concurrency: usize,
+        metrics: Arc<Metrics>,
+    ) -> Self
+    where
+        H: MessageHandler + Clone + 'static,
+    {
+        let count = config.workers.unwrap_or(concurrency).max(1);
+        let workers = (0..count)
+            .map(|worker| {
+                let (tx, mut rx) = mpsc::channel::<Delivery>(config.queue_capacity.max(1));
+                let handler = handler.clone();
+                let processor = processor.clone();
+                let metrics = metrics.clone();
+
+                tokio::spawn(async move {
+                    while let Some(delivery) = rx.recv().await {
+                        metrics.dec_worker_queue_depth(worker);
+
+                        processor
+                            .process(&handler, delivery)
+                            .instrument(tracing::info_span!("ordered_worker", worker))
+                            .await;
+                    }
+                });
+
+                tx
+            })
+            .collect();
+
+        Self {
+            key: config.key.clone(),
+            workers,
+            metrics,
+        }
+    }
+
+    /// Queue a delivery on its key's worker, waiting while that worker's
+    /// queue is full.
+    pub(super) async fn dispatch(&self, delivery: Delivery) {
+        let worker = jump_hash(self.key_hash(&delivery), self.workers.len());
+
+        self.metrics.inc_worker_queue_depth(worker);
+        if let Err(e) = self.workers[worker].send(delivery).await {
+            self.metrics.dec_worker_queue_depth(worker);
+            error!(worker, error = %e, "Ordered worker stopped; delivery left unacknowledged");
+        }
+    }
+
+    fn key_hash(&self, delivery: &Delivery) -> u64 {
+        let key = serde_json::from_slice::<Value>(&delivery.data)
+            .ok()
+            .and_then(|body| body.get(&self.key).cloned());
+
+        let mut hasher = DefaultHasher::new();
+        match key {
+            Some(Value::String(s)) => s.hash(&mut hasher),
+            Some(other) => other.to_string().hash(&mut hasher),
+            None => {
+                warn!(key = %self.key, "Message has no ordering key; using first worker");
+                return 0;
+            }
+        }
+        hasher.finish()
+    }
+}
+
+/// Jump consistent hash (Lamping & Veach): maps a key to one of `buckets`
+/// while moving only about 1/n of keys when a bucket is added.
+fn jump_hash(mut key: u64, buckets: usize) -> usize {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_in_range_and_is_stable() {
+        for key in 0..1000u64 {
+            let bucket = jump_hash(key, 7);
+            assert!(bucket < 7);
+            assert_eq!(jump_hash(key, 7), bucket);
+        }
+        assert_eq!(jump_hash(42, 1), 0);
+    }
+
+    #[test]
+    fn adding_a_bucket_only_moves_keys_to_it() {
+        let mut moved = 0;
+        for key in 0..10_000u64 {
+            let hash = key.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            let before = jump_hash(hash, 10);
+            let after = jump_hash(hash, 11);
+            if before != after {
+                assert_eq!(after, 10);
+                moved += 1;
+            }
+        }
+
+        // About 1/11 of the keys should move
+        assert!((600..1200).contains(&moved), "moved {} keys", moved);
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::Result;
+use futures_util::future::join_all;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use tracing::debug;
+
+use super::headers::json_to_table;
+use super::publisher::{AMQPPublisher, JSON_CONTENT_TYPE};
+use crate::config::BatchFormat;
+use crate::filter::MessageFilter;
+use crate::metrics::Metrics;
+use crate::routing::{Destination, MessageRouter};
+
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// An output message produced by a handler. Without a routing key it is
+/// routed by the pipeline's routing rules; with one it is published as-is
+/// to `exchange` (the default exchange when unset). `headers` become AMQP
+/// headers on the published message, and `content_type` overrides the
+/// default `application/json`.
+#[derive(Debug, Deserialize)]
+pub struct RoutedMessage {
+    #[serde(default)]
+    pub exchange: Option<String>,
+    #[serde(default)]
+    pub routing_key: Option<String>,
+    #[serde(default)]
+    pub headers: Map<String, Value>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub body: Value,
+    /// Set by layers that turn a delivery into a rejection or dead letter,
+    /// so outer layers pass it on untouched. Never read from handler
+    /// output, so a script or wasm module can't set it.
+    #[serde(skip)]
+    pub rejected: bool,
+}
+
+impl RoutedMessage {
+    pub fn new(body: Value) -> Self {
+        Self {
+            exchange: None,
+            routing_key: None,
+            headers: Map::new(),
+            content_type: None,
+            body,
+            rejected: false,
+        }
+    }
+}
+
+/// Outcome of publishing all outputs of one delivery.
+#[derive(Debug, Default)]
+pub struct PublishReport {
+    pub published: usize,
+    pub filtered: usize,
+    pub failed: usize,
+    pub errors: Vec<String>,
+}
+
+/// Last stage shared by every handler: filter, route and publish outputs.
+#[derive(Clone)]
+pub struct OutputStage {
+    publisher: AMQPPublisher,
+    router: Arc<MessageRouter>,
+    filter: Arc<MessageFilter>,
+    metrics: Arc<Metrics>,
+}
+
+impl OutputStage {
+    /// Output stage that publishes everything to `output_queue`.
+    pub fn new(publisher: AMQPPublisher, metrics: Arc<Metrics>, output_queue: &str) -> Self {
+        Self {
+            publisher,
+            router: Arc::new(MessageRouter::to_queue(output_queue)),
+            filter: Arc::new(MessageFilter::default()),
+            metrics,
+        }
+    }
+
+    pub fn with_router(mut self, router: MessageRouter) -> Self {
+        self.router = Arc::new(router);
+        self
+    }
+
+    pub fn with_filter(mut self, filter: MessageFilter) -> Self {
+        self.filter = Arc::new(filter);
+        self
+    }
+
+    /// Publish every output that passes the filters concurrently and wait
+    /// for all confirms, so the caller can settle the delivery once for the
+    /// whole batch.
+    pub async fn publish_all(&self, outputs: &[RoutedMessage], headers: &Value) -> PublishReport {
+        let mut report = PublishReport::default();
+        let mut pending = Vec::with_capacity(outputs.len());
+
+        for output in outputs {
+            match self.filter.matching_rule(&output.body, headers) {
+                Ok(Some(rule)) => {
+                    self.metrics.inc_messages_filtered(rule);
+                    debug!(rule = rule, "Message dropped by filter");
+                    report.filtered += 1;
+                }
+                Ok(None) => pending.push(self.publish_one(output, headers)),
+                Err(e) => {
+                    report.failed += 1;
+                    report.errors.push(e.to_string());
+                }
+            }
+        }
+
+        for result in join_all(pending).await {
+            match result {
+                Ok(()) => report.published += 1,
+                Err(e) => {
+                    report.failed += 1;
+                    report.errors.push(e.to_string());
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Apply filters and resolve destinations without publishing, for callers
+    /// that publish outputs together later.
+    pub fn route_all(&self, outputs: Vec<RoutedMessage>, headers: &Value) -> Result<Vec<(Destination, Value)>> {
+        let mut routed = Vec::with_capacity(outputs.len());
+
+        for output in outputs {
+            if let Some(rule) = self.filter.matching_rule(&output.body, headers)? {
+                self.metrics.inc_messages_filtered(rule);
+                debug!(rule = rule, "Message dropped by filter");
+                continue;
+            }
+
+            let destination = self.router.destination(&output, headers)?;
+            routed.push((destination, output.body));
+        }
+
+        Ok(routed)
+    }
+
+    /// Publish several bodies bound for one destination as a single message.
+    pub async fn publish_batch(&self, destination: &Destination, bodies: &[Value], format: BatchFormat) -> Result<()> {
+        let (payload, content_type) = match format {
+            BatchFormat::JsonArray => (serde_json::to_vec(bodies)?, JSON_CONTENT_TYPE),
+            BatchFormat::Ndjson => {
+                let mut payload = Vec::new();
+                for body in bodies {
+                    serde_json::to_writer(&mut payload, body)?;
+                    payload.push(b'\n');
+                }
+                (payload, NDJSON_CONTENT_TYPE)
+            }
+        };
+
+        self.publisher
+            .publish_bytes(&destination.exchange, &destination.routing_key, &payload, content_type)
+            .await?;
+
+        self.metrics
+            .inc_messages_routed_by(&destination.route, bodies.len() as u64);
+
+        Ok(())
+    }
+
+    async fn publish_one(&self, output: &RoutedMessage, headers: &Value) -> Result<()> {
+        let destination = self.router.destination(output, headers)?;
+        let payload = serde_json::to_vec(&output.body)?;
+        self.publisher
+            .publish_bytes_with_headers(
+                &destination.exchange,
+                &destination.routing_key,
+                &payload,
+                output.content_type.as_deref().unwrap_or(JSON_CONTENT_TYPE),
+                json_to_table(&output.headers),
+            )
+            .await?;
+        self.metrics.inc_messages_routed(&destination.route);
+        Ok(())
+    }
+}
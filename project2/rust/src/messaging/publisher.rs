@@ -1,100 +1,145 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use lapin::{
     options::*,
     types::FieldTable,
     BasicProperties, Channel,
 };
-use serde::Serialize;
 use std::sync::Arc;
 use tracing::{info, instrument};
 
+use super::breaker::CircuitBreaker;
+use crate::ratelimit::RateLimiter;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+
 #[derive(Clone)]
 pub struct AMQPPublisher {
     channel: Arc<Channel>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    breaker: Option<Arc<CircuitBreaker>>,
 }
 
 impl AMQPPublisher {
     pub fn new(channel: Channel) -> Self {
         Self {
             channel: Arc::new(channel),
+            rate_limiter: None,
+            breaker: None,
         }
     }
 
-    #[instrument(skip(self, message))]
-    pub async fn publish<T>(&self, queue_name: &str, message: &T) -> Result<()>
-    where
-        T: Serialize,
-    {
-        // Serialize message to JSON
-        let payload = serde_json::to_vec(message)?;
+    /// Hold every publish until the limiter lets it through.
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
 
-        // Declare queue to ensure it exists
-        self.channel
-            .queue_declare(
-                queue_name,
-                QueueDeclareOptions {
-                    durable: true,
-                    ..Default::default()
-                },
-                FieldTable::default(),
-            )
-            .await?;
+    /// Report every publish outcome to the circuit breaker.
+    pub fn with_circuit_breaker(mut self, breaker: Option<Arc<CircuitBreaker>>) -> Self {
+        self.breaker = breaker;
+        self
+    }
 
-        // Publish message
-        self.channel
-            .basic_publish(
-                "",
-                queue_name,
-                BasicPublishOptions::default(),
-                &payload,
-                BasicProperties::default()
-                    .with_content_type("application/json".into())
-                    .with_delivery_mode(2), // Persistent message
-            )
-            .await?
-            .await?; // Wait for confirmation
+    /// Publish an already-encoded payload and wait for the broker's confirm.
+    /// On the default exchange the routing key names the target queue, which
+    /// is declared first so the message isn't silently dropped.
+    pub async fn publish_bytes(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        content_type: &str,
+    ) -> Result<()> {
+        self.publish_bytes_with_headers(
+            exchange,
+            routing_key,
+            payload,
+            content_type,
+            FieldTable::default(),
+        )
+        .await
+    }
 
-        info!(
-            queue = queue_name,
-            message_size = payload.len(),
-            "Message published successfully"
-        );
+    #[instrument(skip(self, payload, headers))]
+    pub async fn publish_bytes_with_headers(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        content_type: &str,
+        headers: FieldTable,
+    ) -> Result<()> {
+        let result = self
+            .publish_confirmed(exchange, routing_key, payload, content_type, headers)
+            .await;
 
-        Ok(())
+        if let Some(breaker) = &self.breaker {
+            match &result {
+                Ok(()) => breaker.record_success(),
+                Err(_) => breaker.record_failure(),
+            }
+        }
+
+        result
     }
 
-    #[instrument(skip(self, message))]
-    pub async fn publish_with_routing_key<T>(
+    async fn publish_confirmed(
         &self,
         exchange: &str,
         routing_key: &str,
-        message: &T,
-    ) -> Result<()>
-    where
-        T: Serialize,
-    {
-        let payload = serde_json::to_vec(message)?;
-
-        self.channel
+        payload: &[u8],
+        content_type: &str,
+        headers: FieldTable,
+    ) -> Result<()> {
+        // Declare queue to ensure it exists
+        if exchange.is_empty() {
+            self.channel
+                .queue_declare(
+                    routing_key,
+                    QueueDeclareOptions {
+                        durable: true,
+                        ..Default::default()
+                    },
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.acquire(payload.len()).await;
+        }
+
+        // Publish message
+        let confirmation = self
+            .channel
             .basic_publish(
                 exchange,
                 routing_key,
                 BasicPublishOptions::default(),
-                &payload,
+                payload,
                 BasicProperties::default()
-                    .with_content_type("application/json".into())
-                    .with_delivery_mode(2),
+                    .with_content_type(content_type.into())
+                    .with_headers(headers)
+                    .with_delivery_mode(2), // Persistent message
             )
             .await?
-            .await?;
+            .await?; // Wait for confirmation
+
+        if confirmation.is_nack() {
+            return Err(anyhow!(
+                "broker rejected message published to exchange '{}' with routing key {}",
+                exchange,
+                routing_key
+            ));
+        }
 
         info!(
             exchange = exchange,
             routing_key = routing_key,
             message_size = payload.len(),
-            "Message published to exchange"
+            "Message published successfully"
         );
 
         Ok(())
     }
-}
\ No newline at end of file
+}
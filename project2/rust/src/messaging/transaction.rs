@@ -0,0 +1,49 @@
+use anyhow::Result;
+use lapin::Channel;
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Channel in AMQP transaction mode shared by the consumer and publisher.
+/// A transaction covers everything done on its channel, so deliveries take
+/// turns: each one publishes its outputs, acks its input and commits while
+/// holding the channel.
+pub(super) struct TransactionalChannel {
+    channel: Arc<Channel>,
+    lock: Mutex<()>,
+}
+
+impl TransactionalChannel {
+    pub(super) fn new(channel: Arc<Channel>) -> Self {
+        Self {
+            channel,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Wait until no other delivery has a transaction open.
+    pub(super) async fn begin(&self) -> Transaction<'_> {
+        Transaction {
+            channel: &self.channel,
+            _turn: self.lock.lock().await,
+        }
+    }
+}
+
+pub(super) struct Transaction<'a> {
+    channel: &'a Channel,
+    _turn: MutexGuard<'a, ()>,
+}
+
+impl Transaction<'_> {
+    pub(super) async fn commit(self) -> Result<()> {
+        self.channel.tx_commit().await?;
+        Ok(())
+    }
+
+    /// Discard everything published and settled so far. The channel stays
+    /// in transaction mode, so whatever follows still needs a commit.
+    pub(super) async fn rollback(&self) -> Result<()> {
+        self.channel.tx_rollback().await?;
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use prometheus::{
-    Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, IntCounter, Opts, Registry,
-    TextEncoder,
+    Counter, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
 };
 use sysinfo::{Pid, System};
 use std::sync::{Arc, Mutex};
@@ -13,6 +13,25 @@ pub struct Metrics {
     pub messages_processed: IntCounter,
     pub messages_failed: IntCounter,
     pub processing_duration: Histogram,
+    pub script_duration: Histogram,
+    pub messages_routed: IntCounterVec,
+    pub messages_filtered: IntCounterVec,
+    pub batch_size: Histogram,
+    pub batch_flush_latency: Histogram,
+    pub ack_latency: Histogram,
+    pub ack_batch_size: Histogram,
+    pub worker_queue_depth: IntGaugeVec,
+    pub concurrency_limit: IntGauge,
+    pub rate_limit_wait: HistogramVec,
+    pub circuit_breaker_state: IntGaugeVec,
+    pub circuit_breaker_transitions: IntCounterVec,
+    pub message_timeouts: IntCounterVec,
+    pub handler_retries: IntCounter,
+    pub duplicates_dropped: IntCounter,
+    pub consumer_cancellations: IntCounter,
+    pub schema_violations: IntCounterVec,
+    pub lookup_hits: IntCounterVec,
+    pub lookup_misses: IntCounterVec,
     
     // Queue metrics
     pub queue_depth: GaugeVec,
@@ -24,7 +43,12 @@ pub struct Metrics {
     
     // Connection metrics
     pub amqp_connections: Gauge,
+    /// Exported as 0 for parity with the Go service; the connection is not
+    /// re-established after it drops.
+    #[allow(dead_code)]
     pub amqp_reconnections: IntCounter,
+    pub amqp_connection_blocked: IntGauge,
+    pub amqp_blocked_duration: Counter,
     
     // System info
     system: Arc<Mutex<System>>,
@@ -56,6 +80,131 @@ impl Metrics {
         ).buckets(vec![0.001, 0.002, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]))
         .unwrap();
 
+        let script_duration = Histogram::with_opts(HistogramOpts::new(
+            "rabbitmq_script_execution_seconds",
+            "Time spent running the transform script for a message",
+        ).buckets(vec![0.0001, 0.0005, 0.001, 0.002, 0.005, 0.01, 0.025, 0.05, 0.1]))
+        .unwrap();
+
+        let messages_routed = IntCounterVec::new(
+            Opts::new(
+                "rabbitmq_messages_routed_total",
+                "Total number of output messages published, by route",
+            ),
+            &["route"],
+        ).unwrap();
+
+        let messages_filtered = IntCounterVec::new(
+            Opts::new(
+                "rabbitmq_messages_filtered_total",
+                "Total number of messages dropped by a filter rule",
+            ),
+            &["rule"],
+        ).unwrap();
+
+        let batch_size = Histogram::with_opts(HistogramOpts::new(
+            "rabbitmq_batch_size_messages",
+            "Number of input messages per published batch",
+        ).buckets(vec![1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0]))
+        .unwrap();
+
+        let batch_flush_latency = Histogram::with_opts(HistogramOpts::new(
+            "rabbitmq_batch_flush_latency_seconds",
+            "Time from the first message of a batch until the batch is confirmed",
+        ).buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]))
+        .unwrap();
+
+        let ack_latency = Histogram::with_opts(HistogramOpts::new(
+            "rabbitmq_ack_latency_seconds",
+            "Time from a message being ready to acknowledge until its ack is sent",
+        ).buckets(vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]))
+        .unwrap();
+
+        let ack_batch_size = Histogram::with_opts(HistogramOpts::new(
+            "rabbitmq_ack_batch_size_messages",
+            "Number of messages acknowledged per ack sent to the broker",
+        ).buckets(vec![1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0]))
+        .unwrap();
+
+        let worker_queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "rabbitmq_worker_queue_depth",
+                "Number of messages waiting for an ordered-processing worker",
+            ),
+            &["worker"],
+        ).unwrap();
+
+        let concurrency_limit = IntGauge::with_opts(Opts::new(
+            "rabbitmq_concurrency_limit",
+            "Current number of messages the consumer may process at once",
+        )).unwrap();
+
+        let rate_limit_wait = HistogramVec::new(
+            HistogramOpts::new(
+                "rabbitmq_rate_limit_wait_seconds",
+                "Time spent waiting for rate limit tokens, by direction",
+            )
+            .buckets(vec![0.0, 0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+            &["direction"],
+        ).unwrap();
+
+        let circuit_breaker_state = IntGaugeVec::new(
+            Opts::new(
+                "rabbitmq_circuit_breaker_state",
+                "Publish circuit breaker state; 1 for the current state",
+            ),
+            &["state"],
+        ).unwrap();
+
+        let circuit_breaker_transitions = IntCounterVec::new(
+            Opts::new(
+                "rabbitmq_circuit_breaker_transitions_total",
+                "Total number of publish circuit breaker state changes",
+            ),
+            &["from", "to"],
+        ).unwrap();
+
+        let message_timeouts = IntCounterVec::new(
+            Opts::new(
+                "rabbitmq_message_timeouts_total",
+                "Total number of messages that exceeded the processing deadline, by stage",
+            ),
+            &["stage"],
+        ).unwrap();
+
+        let handler_retries = IntCounter::with_opts(Opts::new(
+            "rabbitmq_handler_retries_total",
+            "Total number of handler retries by the retry layer",
+        )).unwrap();
+
+        let duplicates_dropped = IntCounter::with_opts(Opts::new(
+            "rabbitmq_duplicates_dropped_total",
+            "Total number of duplicate messages dropped by the dedup layer",
+        )).unwrap();
+
+        let consumer_cancellations = IntCounter::with_opts(Opts::new(
+            "rabbitmq_consumer_cancellations_total",
+            "Total number of times the broker cancelled the consumer",
+        )).unwrap();
+
+        let schema_violations = IntCounterVec::new(
+            Opts::new(
+                "rabbitmq_schema_violations_total",
+                "Total number of schema violations, by schema version and violated schema keyword",
+            ),
+            &["schema_version", "rule"],
+        ).unwrap();
+
+        let lookup_hits = IntCounterVec::new(
+            Opts::new("rabbitmq_lookup_hits_total", "Total number of lookup table hits"),
+            &["table"],
+        ).unwrap();
+
+        let lookup_misses = IntCounterVec::new(
+            Opts::new("rabbitmq_lookup_misses_total", "Total number of lookup table misses"),
+            &["table"],
+        ).unwrap();
+
         let queue_depth = GaugeVec::new(
             Opts::new("rabbitmq_queue_depth", "Number of messages in queue"),
             &["queue_name"],
@@ -86,29 +235,81 @@ impl Metrics {
             "Total number of AMQP reconnections",
         )).unwrap();
 
+        let amqp_connection_blocked = IntGauge::with_opts(Opts::new(
+            "amqp_connection_blocked",
+            "1 while RabbitMQ has blocked the connection (memory or disk alarm)",
+        )).unwrap();
+
+        let amqp_blocked_duration = Counter::with_opts(Opts::new(
+            "amqp_connection_blocked_seconds_total",
+            "Cumulative time the connection has spent blocked by RabbitMQ",
+        )).unwrap();
+
         // Register all metrics
         registry.register(Box::new(messages_received.clone())).unwrap();
         registry.register(Box::new(messages_processed.clone())).unwrap();
         registry.register(Box::new(messages_failed.clone())).unwrap();
         registry.register(Box::new(processing_duration.clone())).unwrap();
+        registry.register(Box::new(script_duration.clone())).unwrap();
+        registry.register(Box::new(messages_routed.clone())).unwrap();
+        registry.register(Box::new(messages_filtered.clone())).unwrap();
+        registry.register(Box::new(batch_size.clone())).unwrap();
+        registry.register(Box::new(batch_flush_latency.clone())).unwrap();
+        registry.register(Box::new(ack_latency.clone())).unwrap();
+        registry.register(Box::new(ack_batch_size.clone())).unwrap();
+        registry.register(Box::new(worker_queue_depth.clone())).unwrap();
+        registry.register(Box::new(concurrency_limit.clone())).unwrap();
+        registry.register(Box::new(rate_limit_wait.clone())).unwrap();
+        registry.register(Box::new(circuit_breaker_state.clone())).unwrap();
+        registry.register(Box::new(circuit_breaker_transitions.clone())).unwrap();
+        registry.register(Box::new(message_timeouts.clone())).unwrap();
+        registry.register(Box::new(handler_retries.clone())).unwrap();
+        registry.register(Box::new(duplicates_dropped.clone())).unwrap();
+        registry.register(Box::new(consumer_cancellations.clone())).unwrap();
+        registry.register(Box::new(schema_violations.clone())).unwrap();
+        registry.register(Box::new(lookup_hits.clone())).unwrap();
+        registry.register(Box::new(lookup_misses.clone())).unwrap();
         registry.register(Box::new(queue_depth.clone())).unwrap();
         registry.register(Box::new(active_consumers.clone())).unwrap();
         registry.register(Box::new(cpu_usage.clone())).unwrap();
         registry.register(Box::new(memory_usage.clone())).unwrap();
         registry.register(Box::new(amqp_connections.clone())).unwrap();
         registry.register(Box::new(amqp_reconnections.clone())).unwrap();
+        registry.register(Box::new(amqp_connection_blocked.clone())).unwrap();
+        registry.register(Box::new(amqp_blocked_duration.clone())).unwrap();
 
         Self {
             messages_received,
             messages_processed,
             messages_failed,
             processing_duration,
+            script_duration,
+            messages_routed,
+            messages_filtered,
+            batch_size,
+            batch_flush_latency,
+            ack_latency,
+            ack_batch_size,
+            worker_queue_depth,
+            concurrency_limit,
+            rate_limit_wait,
+            circuit_breaker_state,
+            circuit_breaker_transitions,
+            message_timeouts,
+            handler_retries,
+            duplicates_dropped,
+            consumer_cancellations,
+            schema_violations,
+            lookup_hits,
+            lookup_misses,
             queue_depth,
             active_consumers,
             cpu_usage,
             memory_usage,
             amqp_connections,
             amqp_reconnections,
+            amqp_connection_blocked,
+            amqp_blocked_duration,
             system: Arc::new(Mutex::new(System::new_all())),
             registry,
         }
@@ -163,10 +364,109 @@ impl Metrics {
         self.messages_failed.inc();
     }
 
+    pub fn inc_messages_processed_by(&self, count: u64) {
+        self.messages_processed.inc_by(count);
+    }
+
+    pub fn inc_messages_failed_by(&self, count: u64) {
+        self.messages_failed.inc_by(count);
+    }
+
     pub fn observe_processing_duration(&self, duration: std::time::Duration) {
         self.processing_duration.observe(duration.as_secs_f64());
     }
 
+    pub fn observe_script_duration(&self, duration: std::time::Duration) {
+        self.script_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn inc_messages_routed(&self, route: &str) {
+        self.messages_routed.with_label_values(&[route]).inc();
+    }
+
+    pub fn inc_messages_routed_by(&self, route: &str, count: u64) {
+        self.messages_routed.with_label_values(&[route]).inc_by(count);
+    }
+
+    pub fn inc_messages_filtered(&self, rule: &str) {
+        self.messages_filtered.with_label_values(&[rule]).inc();
+    }
+
+    pub fn observe_batch(&self, size: usize, latency: std::time::Duration) {
+        self.batch_size.observe(size as f64);
+        self.batch_flush_latency.observe(latency.as_secs_f64());
+    }
+
+    pub fn observe_ack_latency(&self, latency: std::time::Duration) {
+        self.ack_latency.observe(latency.as_secs_f64());
+    }
+
+    pub fn observe_ack_batch_size(&self, size: usize) {
+        self.ack_batch_size.observe(size as f64);
+    }
+
+    pub fn inc_worker_queue_depth(&self, worker: usize) {
+        self.worker_queue_depth.with_label_values(&[&worker.to_string()]).inc();
+    }
+
+    pub fn dec_worker_queue_depth(&self, worker: usize) {
+        self.worker_queue_depth.with_label_values(&[&worker.to_string()]).dec();
+    }
+
+    pub fn set_concurrency_limit(&self, limit: usize) {
+        self.concurrency_limit.set(limit as i64);
+    }
+
+    pub fn observe_rate_limit_wait(&self, direction: &str, wait: std::time::Duration) {
+        self.rate_limit_wait
+            .with_label_values(&[direction])
+            .observe(wait.as_secs_f64());
+    }
+
+    pub fn set_circuit_breaker_state(&self, state: &str) {
+        for label in ["closed", "open", "half_open"] {
+            self.circuit_breaker_state
+                .with_label_values(&[label])
+                .set((label == state) as i64);
+        }
+    }
+
+    pub fn inc_circuit_breaker_transitions(&self, from: &str, to: &str) {
+        self.circuit_breaker_transitions
+            .with_label_values(&[from, to])
+            .inc();
+    }
+
+    pub fn inc_message_timeouts(&self, stage: &str) {
+        self.message_timeouts.with_label_values(&[stage]).inc();
+    }
+
+    pub fn inc_handler_retries(&self) {
+        self.handler_retries.inc();
+    }
+
+    pub fn inc_duplicates_dropped(&self) {
+        self.duplicates_dropped.inc();
+    }
+
+    pub fn inc_consumer_cancellations(&self) {
+        self.consumer_cancellations.inc();
+    }
+
+    pub fn inc_schema_violations(&self, schema_version: &str, rule: &str) {
+        self.schema_violations
+            .with_label_values(&[schema_version, rule])
+            .inc();
+    }
+
+    pub fn inc_lookup_hits(&self, table: &str) {
+        self.lookup_hits.with_label_values(&[table]).inc();
+    }
+
+    pub fn inc_lookup_misses(&self, table: &str) {
+        self.lookup_misses.with_label_values(&[table]).inc();
+    }
+
     pub fn set_queue_depth(&self, queue_name: &str, depth: f64) {
         self.queue_depth.with_label_values(&[queue_name]).set(depth);
     }
@@ -179,7 +479,16 @@ impl Metrics {
         self.amqp_connections.set(count);
     }
 
+    #[allow(dead_code)]
     pub fn inc_amqp_reconnections(&self) {
         self.amqp_reconnections.inc();
     }
+
+    pub fn set_amqp_connection_blocked(&self, blocked: bool) {
+        self.amqp_connection_blocked.set(blocked as i64);
+    }
+
+    pub fn add_amqp_blocked_duration(&self, duration: std::time::Duration) {
+        self.amqp_blocked_duration.inc_by(duration.as_secs_f64());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,214 @@
+use anyhow::{anyhow, Result};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::config::{MoneyConfig, RoundingMode};
+
+/// Serde adapter keeping decimal amounts as plain JSON numbers on the wire.
+///
+/// Without serde_json's `arbitrary_precision` the parser hands us an `f64`,
+/// so the amount is rebuilt from the float's shortest round-trip text: a
+/// producer that wrote `999.99` gets exactly `999.99`, not the nearest
+/// binary fraction.
+pub mod json_number {
+    use rust_decimal::prelude::ToPrimitive;
+    use rust_decimal::Decimal;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+        match value.to_f64() {
+            Some(number) => serializer.serialize_f64(number),
+            None => Err(serde::ser::Error::custom(format!(
+                "{} cannot be written as a JSON number",
+                value
+            ))),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        let number = serde_json::Number::deserialize(deserializer)?;
+        super::from_json_number(&number).map_err(de::Error::custom)
+    }
+}
+
+/// Exact decimal for a JSON number, or an error if it has more digits than
+/// a decimal can hold.
+pub fn from_json_number(number: &serde_json::Number) -> Result<Decimal> {
+    if let Some(integer) = number.as_i64() {
+        return Ok(Decimal::from(integer));
+    }
+    if let Some(integer) = number.as_u64() {
+        return Ok(Decimal::from(integer));
+    }
+
+    let float = number
+        .as_f64()
+        .ok_or_else(|| anyhow!("{} is not a number", number))?;
+    Decimal::from_str_exact(&float.to_string())
+        .map_err(|e| anyhow!("{} cannot be represented as a decimal: {}", number, e))
+}
+
+/// How monetary amounts are rounded and checked before being forwarded.
+#[derive(Debug, Clone)]
+pub struct MoneyPolicy {
+    scale: u32,
+    rounding: RoundingStrategy,
+    currency: Option<String>,
+    strict: bool,
+}
+
+impl MoneyPolicy {
+    pub fn from_config(config: &MoneyConfig) -> Result<Self> {
+        if config.scale > 28 {
+            return Err(anyhow!("money scale must be at most 28, got {}", config.scale));
+        }
+        if let Some(currency) = &config.currency {
+            validate_currency(currency)?;
+        }
+
+        Ok(Self {
+            scale: config.scale,
+            rounding: match config.rounding {
+                RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+                RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+                RoundingMode::HalfDown => RoundingStrategy::MidpointTowardZero,
+                RoundingMode::Down => RoundingStrategy::ToZero,
+                RoundingMode::Up => RoundingStrategy::AwayFromZero,
+                RoundingMode::Floor => RoundingStrategy::ToNegativeInfinity,
+                RoundingMode::Ceiling => RoundingStrategy::ToPositiveInfinity,
+            },
+            currency: config.currency.clone(),
+            strict: config.strict,
+        })
+    }
+
+    /// Bring `amount` to the configured scale. In strict mode an amount
+    /// with more decimal places than the scale is an error instead of being
+    /// rounded.
+    pub fn normalize(&self, field: &str, amount: Decimal) -> Result<Decimal> {
+        let normalized = amount.normalize();
+        if normalized.scale() > self.scale {
+            if self.strict {
+                return Err(anyhow!(
+                    "{} {} has more than {} decimal places",
+                    field,
+                    amount,
+                    self.scale
+                ));
+            }
+            let rounded = normalized.round_dp_with_strategy(self.scale, self.rounding);
+            return check_float_exact(field, rounded);
+        }
+        check_float_exact(field, normalized)
+    }
+
+    /// Currency for an output message: the message's own code if it has
+    /// one, otherwise the configured default. A message in a different
+    /// currency than the configured one is rejected.
+    pub fn currency(&self, input: Option<&str>) -> Result<Option<String>> {
+        match (input, &self.currency) {
+            (Some(input), Some(configured)) if input != configured => Err(anyhow!(
+                "currency {} does not match configured currency {}",
+                input,
+                configured
+            )),
+            (Some(input), _) => {
+                validate_currency(input)?;
+                Ok(Some(input.to_string()))
+            }
+            (None, configured) => Ok(configured.clone()),
+        }
+    }
+}
+
+/// The wire format is a JSON number, so refuse amounts that would not read
+/// back as the same decimal.
+fn check_float_exact(field: &str, amount: Decimal) -> Result<Decimal> {
+    let round_trip = amount
+        .to_f64()
+        .and_then(|float| Decimal::from_str_exact(&float.to_string()).ok());
+
+    match round_trip {
+        Some(back) if back == amount => Ok(amount),
+        _ => Err(anyhow!(
+            "{} {} cannot be represented exactly as a JSON number",
+            field,
+            amount
+        )),
+    }
+}
+
+fn validate_currency(code: &str) -> Result<()> {
+    if code.len() == 3 && code.chars().all(|c| c.is_ascii_uppercase()) {
+        Ok(())
+    } else {
+        Err(anyhow!("currency must be a three-letter ISO 4217 code, got '{}'", code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy(config: serde_json::Value) -> MoneyPolicy {
+        MoneyPolicy::from_config(&serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    fn decimal(source: &str) -> Decimal {
+        source.parse().unwrap()
+    }
+
+    #[test]
+    fn reads_json_numbers_exactly() {
+        let number =
+            |value: serde_json::Value| from_json_number(value.as_number().unwrap()).unwrap();
+
+        assert_eq!(number(json!(999.99)), decimal("999.99"));
+        assert_eq!(number(json!(0.1)), decimal("0.1"));
+        assert_eq!(number(json!(u64::MAX)), Decimal::from(u64::MAX));
+        assert!(from_json_number(json!(1e300).as_number().unwrap()).is_err());
+    }
+
+    #[test]
+    fn rounds_to_scale_with_the_configured_strategy() {
+        let half_even = policy(json!({"scale": 2, "rounding": "half_even"}));
+        let half_up = policy(json!({"scale": 2, "rounding": "half_up"}));
+
+        assert_eq!(half_even.normalize("price", decimal("2.345")).unwrap(), decimal("2.34"));
+        assert_eq!(half_up.normalize("price", decimal("2.345")).unwrap(), decimal("2.35"));
+        assert_eq!(half_up.normalize("price", decimal("2.50")).unwrap(), decimal("2.5"));
+    }
+
+    #[test]
+    fn strict_mode_rejects_extra_places() {
+        let strict = policy(json!({"scale": 2, "strict": true}));
+
+        assert!(strict.normalize("price", decimal("2.345")).is_err());
+        assert_eq!(strict.normalize("price", decimal("2.340")).unwrap(), decimal("2.34"));
+    }
+
+    #[test]
+    fn rejects_amounts_a_json_number_cannot_hold() {
+        let policy = policy(json!({"scale": 28}));
+
+        assert!(policy
+            .normalize("price", decimal("0.1234567890123456789012345678"))
+            .is_err());
+    }
+
+    #[test]
+    fn defaults_and_checks_currency() {
+        let eur = policy(json!({"currency": "EUR"}));
+        let any = policy(json!({}));
+
+        assert_eq!(eur.currency(None).unwrap().as_deref(), Some("EUR"));
+        assert_eq!(eur.currency(Some("EUR")).unwrap().as_deref(), Some("EUR"));
+        assert!(eur.currency(Some("USD")).is_err());
+        assert_eq!(any.currency(None).unwrap(), None);
+        assert!(any.currency(Some("usd")).is_err());
+
+        let config = serde_json::from_value(json!({"currency": "EURO"})).unwrap();
+        assert!(MoneyPolicy::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::config::{RateLimitConfig, RateLimits};
+use crate::metrics::Metrics;
+
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket holding up to one second's worth of tokens. Takers may
+/// overdraw it, and then wait until the debt is paid back, so a request
+/// larger than the bucket (a big message against a bytes limit) is delayed
+/// rather than refused. `None` means unlimited.
+struct TokenBucket {
+    state: Mutex<Option<Bucket>>,
+}
+
+impl TokenBucket {
+    fn new(rate: Option<f64>) -> Self {
+        Self {
+            state: Mutex::new(rate.map(|rate| Bucket {
+                rate,
+                tokens: rate,
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    fn rate(&self) -> Option<f64> {
+        self.state.lock().unwrap().as_ref().map(|bucket| bucket.rate)
+    }
+
+    fn set_rate(&self, rate: Option<f64>) {
+        let mut state = self.state.lock().unwrap();
+        *state = rate.map(|rate| match state.take() {
+            Some(mut bucket) => {
+                bucket.refill();
+                bucket.rate = rate;
+                bucket.tokens = bucket.tokens.min(rate);
+                bucket
+            }
+            None => Bucket {
+                rate,
+                tokens: rate,
+                updated: Instant::now(),
+            },
+        });
+    }
+
+    /// Take `amount` tokens and return how long the caller must wait before
+    /// using them.
+    fn reserve(&self, amount: f64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let Some(bucket) = state.as_mut() else {
+            return Duration::ZERO;
+        };
+
+        bucket.refill();
+        bucket.tokens -= amount;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+        }
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+}
+
+/// Message and byte rate limits for one direction of traffic. Limits can
+/// be changed while running; callers already waiting keep their delay.
+pub struct RateLimiter {
+    direction: &'static str,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    metrics: Arc<Metrics>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        direction: &'static str,
+        config: &RateLimitConfig,
+        metrics: Arc<Metrics>,
+    ) -> Result<Arc<Self>> {
+        validate(config)?;
+
+        Ok(Arc::new(Self {
+            direction,
+            messages: TokenBucket::new(config.messages_per_sec),
+            bytes: TokenBucket::new(config.bytes_per_sec),
+            metrics,
+        }))
+    }
+
+    pub fn limits(&self) -> RateLimitConfig {
+        RateLimitConfig {
+            messages_per_sec: self.messages.rate(),
+            bytes_per_sec: self.bytes.rate(),
+        }
+    }
+
+    pub fn set_limits(&self, config: &RateLimitConfig) -> Result<()> {
+        validate(config)?;
+
+        self.messages.set_rate(config.messages_per_sec);
+        self.bytes.set_rate(config.bytes_per_sec);
+        Ok(())
+    }
+
+    /// Wait until one message of `size` bytes may pass.
+    pub async fn acquire(&self, size: usize) {
+        let wait = self
+            .messages
+            .reserve(1.0)
+            .max(self.bytes.reserve(size as f64));
+
+        self.metrics.observe_rate_limit_wait(self.direction, wait);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Runtime control over the consume and publish limiters, served as
+/// `/rate-limits` on the admin listener (`app.admin_addr`, 127.0.0.1:8084 by
+/// default) rather than the public HTTP port.
+#[derive(Clone)]
+pub struct RateLimitControl {
+    pub consume: Arc<RateLimiter>,
+    pub publish: Arc<RateLimiter>,
+}
+
+/// New limits for either direction; an omitted direction is left as is.
+#[derive(Debug, Deserialize)]
+pub struct RateLimitsUpdate {
+    #[serde(default)]
+    pub consume: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub publish: Option<RateLimitConfig>,
+}
+
+impl RateLimitControl {
+    pub fn from_config(config: &RateLimits, metrics: Arc<Metrics>) -> Result<Self> {
+        Ok(Self {
+            consume: RateLimiter::new("consume", &config.consume, metrics.clone())?,
+            publish: RateLimiter::new("publish", &config.publish, metrics)?,
+        })
+    }
+
+    pub fn current(&self) -> RateLimits {
+        RateLimits {
+            consume: self.consume.limits(),
+            publish: self.publish.limits(),
+        }
+    }
+
+    /// Apply an update, leaving both limiters untouched if any rate is invalid.
+    pub fn update(&self, update: &RateLimitsUpdate) -> Result<RateLimits> {
+        for config in [&update.consume, &update.publish].into_iter().flatten() {
+            validate(config)?;
+        }
+
+        if let Some(config) = &update.consume {
+            self.consume.set_limits(config)?;
+        }
+        if let Some(config) = &update.publish {
+            self.publish.set_limits(config)?;
+        }
+
+        info!(limits = ?self.current(), "Rate limits updated");
+        Ok(self.current())
+    }
+}
+
+fn validate(config: &RateLimitConfig) -> Result<()> {
+    for (name, rate) in [
+        ("messages_per_sec", config.messages_per_sec),
+        ("bytes_per_sec", config.bytes_per_sec),
+    ] {
+        if let Some(rate) = rate {
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(anyhow!("{} must be a positive number, got {}", name, rate));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_of_one_second_then_delays() {
+        let bucket = TokenBucket::new(Some(10.0));
+
+        for _ in 0..10 {
+            assert_eq!(bucket.reserve(1.0), Duration::ZERO);
+        }
+        let wait = bucket.reserve(1.0);
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn delays_requests_larger_than_the_bucket() {
+        let bucket = TokenBucket::new(Some(100.0));
+
+        let wait = bucket.reserve(300.0);
+
+        assert!(wait > Duration::from_millis(1990) && wait <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn unlimited_without_a_rate() {
+        let bucket = TokenBucket::new(None);
+
+        assert_eq!(bucket.reserve(1e12), Duration::ZERO);
+        assert_eq!(bucket.rate(), None);
+    }
+
+    #[test]
+    fn lowering_the_rate_caps_saved_tokens() {
+        let bucket = TokenBucket::new(Some(100.0));
+
+        bucket.set_rate(Some(2.0));
+
+        assert_eq!(bucket.rate(), Some(2.0));
+        assert_eq!(bucket.reserve(2.0), Duration::ZERO);
+        assert!(bucket.reserve(1.0) > Duration::ZERO);
+    }
+
+    #[test]
+    fn invalid_update_changes_neither_direction() {
+        let control = RateLimitControl::from_config(
+            &RateLimits::default(),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap();
+        let update: RateLimitsUpdate = serde_json::from_value(serde_json::json!({
+            "consume": {"messages_per_sec": 5.0},
+            "publish": {"messages_per_sec": -1.0}
+        }))
+        .unwrap();
+
+        assert!(control.update(&update).is_err());
+        assert_eq!(control.consume.limits().messages_per_sec, None);
+    }
+}
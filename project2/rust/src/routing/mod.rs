@@ -0,0 +1,232 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::config::{RouteTarget, Routing};
+use crate::expr::Expression;
+use crate::messaging::RoutedMessage;
+
+/// Route name used when no rule matches.
+pub const DEFAULT_ROUTE: &str = "default";
+
+/// Route name for outputs whose script or plugin chose the routing key.
+pub const EXPLICIT_ROUTE: &str = "explicit";
+
+/// Where a single output message should be published.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Destination {
+    pub route: String,
+    pub exchange: String,
+    pub routing_key: String,
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    name: String,
+    when: Expression,
+    exchange: String,
+    routing_key: String,
+}
+
+/// Content-based router built from `pipeline.routing`. Rules are evaluated in
+/// order and the first match wins.
+#[derive(Debug, Clone)]
+pub struct MessageRouter {
+    routes: Vec<Route>,
+    default_exchange: String,
+    default_routing_key: String,
+}
+
+impl MessageRouter {
+    /// Build a router without rules that publishes every output to `queue`
+    /// through the default exchange.
+    pub fn to_queue(queue: &str) -> Self {
+        Self {
+            routes: Vec::new(),
+            default_exchange: String::new(),
+            default_routing_key: queue.to_string(),
+        }
+    }
+
+    /// Build the router, falling back to `output_queue` on the default
+    /// exchange when no default route is configured.
+    pub fn from_config(routing: &Routing, output_queue: &str) -> Result<Self> {
+        let routes = routing
+            .routes
+            .iter()
+            .map(|route| {
+                Ok(Route {
+                    name: route.name.clone(),
+                    when: Expression::parse(&route.when)?,
+                    exchange: route.exchange.clone(),
+                    routing_key: route.routing_key.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let default = routing.default.clone().unwrap_or_else(|| RouteTarget {
+            exchange: String::new(),
+            routing_key: output_queue.to_string(),
+        });
+
+        Ok(Self {
+            routes,
+            default_exchange: default.exchange,
+            default_routing_key: default.routing_key,
+        })
+    }
+
+    /// Resolve a handler output. An explicit routing key is used as-is on the
+    /// output's exchange; otherwise the routing rules apply.
+    pub fn destination(&self, output: &RoutedMessage, headers: &Value) -> Result<Destination> {
+        match &output.routing_key {
+            Some(routing_key) => Ok(Destination {
+                route: EXPLICIT_ROUTE.to_string(),
+                exchange: output.exchange.clone().unwrap_or_default(),
+                routing_key: routing_key.clone(),
+            }),
+            None => self.resolve(&output.body, headers),
+        }
+    }
+
+    pub fn resolve(&self, message: &Value, headers: &Value) -> Result<Destination> {
+        for route in &self.routes {
+            if route.when.matches(message, headers)? {
+                return Ok(Destination {
+                    route: route.name.clone(),
+                    exchange: route.exchange.clone(),
+                    routing_key: render_template(&route.routing_key, message, headers)?,
+                });
+            }
+        }
+
+        Ok(Destination {
+            route: DEFAULT_ROUTE.to_string(),
+            exchange: self.default_exchange.clone(),
+            routing_key: render_template(&self.default_routing_key, message, headers)?,
+        })
+    }
+}
+
+/// Expand `{field}` placeholders in a template such as a routing key.
+/// Dotted paths reach into nested objects, and `{headers.name}` reads an
+/// AMQP header.
+pub fn render_template(template: &str, message: &Value, headers: &Value) -> Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("unterminated placeholder in template `{}`", template))?;
+        let path = &rest[start + 1..end];
+
+        let value = match path.strip_prefix("headers.") {
+            Some(header) => lookup(headers, header),
+            None => lookup(message, path),
+        }
+        .ok_or_else(|| anyhow!("template `{}` references missing field `{}`", template, path))?;
+
+        match value {
+            Value::String(s) => rendered.push_str(s),
+            other => rendered.push_str(&other.to_string()),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(value, |current, key| current.get(key))
+        .filter(|value| !value.is_null())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn router() -> MessageRouter {
+        let routing: Routing = serde_json::from_value(json!({
+            "routes": [
+                {
+                    "name": "bulk",
+                    "when": "quantity >= 100",
+                    "exchange": "orders",
+                    "routing_key": "orders.bulk.{customer.region}"
+                },
+                {
+                    "name": "priority",
+                    "when": "headers.priority == \"high\"",
+                    "routing_key": "orders.priority"
+                }
+            ]
+        }))
+        .unwrap();
+        MessageRouter::from_config(&routing, "output").unwrap()
+    }
+
+    #[test]
+    fn renders_fields_and_headers() {
+        let message = json!({"id": 7, "customer": {"region": "eu"}});
+        let headers = json!({"tenant": "acme"});
+
+        assert_eq!(
+            render_template("{headers.tenant}.{customer.region}.{id}", &message, &headers).unwrap(),
+            "acme.eu.7"
+        );
+        assert_eq!(render_template("static", &message, &headers).unwrap(), "static");
+    }
+
+    #[test]
+    fn rejects_missing_fields_and_unterminated_placeholders() {
+        let message = json!({"id": null});
+
+        assert!(render_template("{id}", &message, &json!({})).is_err());
+        assert!(render_template("orders.{region", &message, &json!({})).is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let router = router();
+        let message = json!({"quantity": 500, "customer": {"region": "us"}});
+
+        let destination = router.resolve(&message, &json!({"priority": "high"})).unwrap();
+
+        assert_eq!(destination.route, "bulk");
+        assert_eq!(destination.exchange, "orders");
+        assert_eq!(destination.routing_key, "orders.bulk.us");
+    }
+
+    #[test]
+    fn matches_on_headers_and_falls_back_to_default() {
+        let router = router();
+        let message = json!({"quantity": 1});
+
+        let priority = router.resolve(&message, &json!({"priority": "high"})).unwrap();
+        assert_eq!(priority.route, "priority");
+        assert_eq!(priority.routing_key, "orders.priority");
+
+        let default = router.resolve(&message, &json!({})).unwrap();
+        assert_eq!(default.route, DEFAULT_ROUTE);
+        assert_eq!(default.exchange, "");
+        assert_eq!(default.routing_key, "output");
+    }
+
+    #[test]
+    fn explicit_routing_key_bypasses_rules() {
+        let mut output = RoutedMessage::new(json!({"quantity": 500}));
+        output.routing_key = Some("audit".to_string());
+
+        let destination = router().destination(&output, &json!({})).unwrap();
+
+        assert_eq!(destination.route, EXPLICIT_ROUTE);
+        assert_eq!(destination.routing_key, "audit");
+    }
+}
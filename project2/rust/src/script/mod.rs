@@ -0,0 +1,213 @@
+use anyhow::{anyhow, Context, Result};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+use serde_json::Value;
+use std::cell::Cell;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+use crate::config::ScriptConfig;
+use crate::messaging::RoutedMessage;
+use crate::watcher;
+
+/// Name of the function every transform script must define.
+const ENTRY_POINT: &str = "process";
+
+thread_local! {
+    // Deadline for the script running on this thread, checked by the
+    // engine's progress callback.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Sandboxed Rhai script that turns one decoded message into zero or more
+/// output messages. The compiled script is swapped in place when the file
+/// changes on disk.
+pub struct ScriptRunner {
+    engine: Engine,
+    ast: RwLock<Arc<AST>>,
+    path: PathBuf,
+    timeout: Duration,
+    exchange: String,
+}
+
+impl ScriptRunner {
+    pub fn load(config: &ScriptConfig) -> Result<Arc<Self>> {
+        let mut engine = Engine::new();
+        // Scripts must not load other files from disk through `import`
+        engine.set_module_resolver(DummyModuleResolver::new());
+        engine
+            .set_max_operations(config.max_operations)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(1 << 20)
+            .set_max_array_size(10_000)
+            .set_max_map_size(10_000)
+            .disable_symbol("eval")
+            .on_progress(|_| {
+                let expired = DEADLINE.with(|deadline| {
+                    deadline.get().is_some_and(|deadline| Instant::now() >= deadline)
+                });
+                expired.then(|| Dynamic::from("script time limit exceeded"))
+            })
+            .on_print(|text| info!(script_output = text, "Script print"));
+
+        let path = PathBuf::from(&config.path);
+        let ast = compile(&engine, &path)?;
+
+        let runner = Arc::new(Self {
+            engine,
+            ast: RwLock::new(Arc::new(ast)),
+            path: path.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            exchange: config.exchange.clone(),
+        });
+
+        let watched = Arc::downgrade(&runner);
+        watcher::watch_file(
+            path,
+            Duration::from_millis(config.reload_interval_ms),
+            move || match watched.upgrade() {
+                Some(runner) => {
+                    runner.reload();
+                    ControlFlow::Continue(())
+                }
+                None => ControlFlow::Break(()),
+            },
+        );
+
+        info!(path = %config.path, "Transform script loaded");
+
+        Ok(runner)
+    }
+
+    /// Recompile the script, keeping the previous version if it fails.
+    pub fn reload(&self) {
+        match compile(&self.engine, &self.path) {
+            Ok(ast) => {
+                *self.ast.write().unwrap() = Arc::new(ast);
+                info!(path = %self.path.display(), "Transform script reloaded");
+            }
+            Err(e) => {
+                error!(
+                    path = %self.path.display(),
+                    error = %e,
+                    "Failed to reload transform script, keeping previous version"
+                );
+            }
+        }
+    }
+
+    /// Run the script synchronously. CPU-bound, so callers should run it on a
+    /// blocking thread.
+    pub fn run(&self, message: Value, headers: Value) -> Result<Vec<RoutedMessage>> {
+        let ast = self.ast.read().unwrap().clone();
+        let message = rhai::serde::to_dynamic(message).map_err(|e| anyhow!(e.to_string()))?;
+        let headers = rhai::serde::to_dynamic(headers).map_err(|e| anyhow!(e.to_string()))?;
+
+        DEADLINE.with(|deadline| deadline.set(Some(Instant::now() + self.timeout)));
+        let result = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &ast, ENTRY_POINT, (message, headers));
+        DEADLINE.with(|deadline| deadline.set(None));
+
+        let result = result.map_err(|e| anyhow!("script `{}` failed: {}", self.path.display(), e))?;
+
+        // A script may return nothing, a single output, or an array of outputs
+        let outputs = if result.is_unit() {
+            Vec::new()
+        } else if result.is_array() {
+            result.cast::<Array>()
+        } else {
+            vec![result]
+        };
+
+        outputs
+            .iter()
+            .map(|output| {
+                let mut output = rhai::serde::from_dynamic::<RoutedMessage>(output)
+                    .map_err(|e| anyhow!("invalid script output: {}", e))?;
+                output.exchange.get_or_insert_with(|| self.exchange.clone());
+                Ok(output)
+            })
+            .collect()
+    }
+}
+
+fn compile(engine: &Engine, path: &Path) -> Result<AST> {
+    let ast = engine
+        .compile_file(path.to_path_buf())
+        .map_err(|e| anyhow!(e.to_string()))
+        .with_context(|| format!("failed to compile script `{}`", path.display()))?;
+
+    if !ast.iter_functions().any(|f| f.name == ENTRY_POINT) {
+        return Err(anyhow!(
+            "script `{}` does not define `fn {}(message, headers)`",
+            path.display(),
+            ENTRY_POINT
+        ));
+    }
+
+    Ok(ast)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("script_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn load(dir: &Path, source: &str) -> Arc<ScriptRunner> {
+        let path = dir.join("script.rhai");
+        std::fs::write(&path, source).unwrap();
+
+        ScriptRunner::load(&ScriptConfig {
+            path: path.to_string_lossy().into_owned(),
+            exchange: String::new(),
+            max_operations: 10_000,
+            timeout_ms: 1_000,
+            reload_interval_ms: 60_000,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn routes_script_outputs() {
+        let dir = temp_dir();
+        let runner = load(
+            &dir,
+            r#"fn process(message, headers) { [#{ routing_key: "bulk", body: message }, #{ body: message }] }"#,
+        );
+
+        let outputs = runner.run(json!({"quantity": 1}), json!({})).unwrap();
+
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].routing_key.as_deref(), Some("bulk"));
+        assert_eq!(outputs[0].exchange.as_deref(), Some(""));
+        assert_eq!(outputs[1].body, json!({"quantity": 1}));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cannot_import_files() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("module.rhai"), "export const SECRET = 1;").unwrap();
+        let runner = load(
+            &dir,
+            &format!(
+                r#"fn process(message, headers) {{ import "{}" as m; #{{ body: m::SECRET }} }}"#,
+                dir.join("module").display()
+            ),
+        );
+
+        assert!(runner.run(json!({}), json!({})).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::{anyhow, Result};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+use crate::config::{TransformConfig, UuidVersion};
+use crate::expr::Expression;
+
+/// Ordered list of transform steps built from `pipeline.transforms`.
+#[derive(Debug, Clone, Default)]
+pub struct TransformPipeline {
+    steps: Vec<PipelineStep>,
+}
+
+/// Split steps change the number of messages, so the pipeline runs them
+/// itself; every other step edits one message in place.
+#[derive(Debug, Clone)]
+enum PipelineStep {
+    Map(Step),
+    Split { field: String, into: Option<String> },
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    Rename { from: String, to: String },
+    Drop { field: String },
+    Set { field: String, value: Value },
+    Compute { field: String, expr: Expression },
+    Timestamp { field: String },
+    Uuid { field: String, version: UuidVersion },
+}
+
+impl TransformPipeline {
+    pub fn from_config(transforms: &[TransformConfig]) -> Result<Self> {
+        let steps = transforms
+            .iter()
+            .map(|transform| {
+                let step = match transform {
+                    TransformConfig::Split { field, into } => {
+                        return Ok(PipelineStep::Split {
+                            field: field.clone(),
+                            into: into.clone(),
+                        })
+                    }
+                    TransformConfig::Rename { from, to } => Step::Rename {
+                        from: from.clone(),
+                        to: to.clone(),
+                    },
+                    TransformConfig::Drop { field } => Step::Drop {
+                        field: field.clone(),
+                    },
+                    TransformConfig::Set { field, value } => Step::Set {
+                        field: field.clone(),
+                        value: value.clone(),
+                    },
+                    TransformConfig::Compute { field, expr } => Step::Compute {
+                        field: field.clone(),
+                        expr: Expression::parse(expr)?,
+                    },
+                    TransformConfig::Timestamp { field } => Step::Timestamp {
+                        field: field.clone(),
+                    },
+                    TransformConfig::Uuid { field, version } => Step::Uuid {
+                        field: field.clone(),
+                        version: *version,
+                    },
+                };
+                Ok(PipelineStep::Map(step))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { steps })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Apply every step in order. Split steps can turn one message into
+    /// several; later steps run on each of them.
+    pub fn apply(&self, message: Value) -> Result<Vec<Value>> {
+        if self.steps.is_empty() {
+            return Ok(vec![message]);
+        }
+
+        let mut messages = match message {
+            Value::Object(fields) => vec![fields],
+            other => return Err(anyhow!("expected a JSON object, got {}", type_name(&other))),
+        };
+
+        for step in &self.steps {
+            messages = match step {
+                PipelineStep::Split { field, into } => messages
+                    .into_iter()
+                    .map(|fields| split(fields, field, into.as_deref()))
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .flatten()
+                    .collect(),
+                PipelineStep::Map(step) => {
+                    for fields in &mut messages {
+                        step.apply(fields)?;
+                    }
+                    messages
+                }
+            };
+        }
+
+        Ok(messages.into_iter().map(Value::Object).collect())
+    }
+}
+
+fn split(mut fields: Map<String, Value>, field: &str, into: Option<&str>) -> Result<Vec<Map<String, Value>>> {
+    let items = match fields.remove(field) {
+        Some(Value::Array(items)) => items,
+        Some(other) => {
+            return Err(anyhow!(
+                "cannot split `{}`: expected an array, got {}",
+                field,
+                type_name(&other)
+            ))
+        }
+        None => return Err(anyhow!("cannot split `{}`: field is missing", field)),
+    };
+
+    Ok(items
+        .into_iter()
+        .map(|item| {
+            let mut message = fields.clone();
+            match (into, item) {
+                (None, Value::Object(item_fields)) => message.extend(item_fields),
+                (None, item) => {
+                    message.insert(field.to_string(), item);
+                }
+                (Some(into), item) => {
+                    message.insert(into.to_string(), item);
+                }
+            }
+            message
+        })
+        .collect())
+}
+
+impl Step {
+    fn apply(&self, fields: &mut Map<String, Value>) -> Result<()> {
+        match self {
+            Step::Rename { from, to } => {
+                if let Some(value) = fields.remove(from) {
+                    fields.insert(to.clone(), value);
+                }
+            }
+            Step::Drop { field } => {
+                fields.remove(field);
+            }
+            Step::Set { field, value } => {
+                fields.insert(field.clone(), value.clone());
+            }
+            Step::Compute { field, expr } => {
+                // Fields written by earlier steps are visible to the expression
+                let value = expr.eval(&Value::Object(fields.clone()))?;
+                fields.insert(field.clone(), value);
+            }
+            Step::Timestamp { field } => {
+                let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+                fields.insert(field.clone(), Value::String(now));
+            }
+            Step::Uuid { field, version } => {
+                let id = match version {
+                    UuidVersion::V4 => Uuid::new_v4(),
+                    UuidVersion::V7 => Uuid::now_v7(),
+                };
+                fields.insert(field.clone(), Value::String(id.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pipeline(transforms: Value) -> TransformPipeline {
+        let config: Vec<TransformConfig> = serde_json::from_value(transforms).unwrap();
+        TransformPipeline::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn applies_steps_in_order() {
+        let pipeline = pipeline(json!([
+            {"type": "rename", "from": "qty", "to": "quantity"},
+            {"type": "drop", "field": "internal"},
+            {"type": "set", "field": "source", "value": "rust"},
+            {"type": "compute", "field": "total", "expr": "price * quantity"},
+        ]));
+
+        let output = pipeline
+            .apply(json!({"qty": 3, "price": 2, "internal": true}))
+            .unwrap();
+
+        assert_eq!(
+            output,
+            vec![json!({"quantity": 3, "price": 2, "source": "rust", "total": 6})]
+        );
+    }
+
+    #[test]
+    fn adds_uuid_and_timestamp_fields() {
+        let pipeline = pipeline(json!([
+            {"type": "uuid", "field": "id", "version": "v7"},
+            {"type": "timestamp", "field": "at"},
+        ]));
+
+        let output = pipeline.apply(json!({})).unwrap();
+
+        let id = output[0]["id"].as_str().unwrap();
+        assert_eq!(Uuid::parse_str(id).unwrap().get_version_num(), 7);
+        assert!(chrono::DateTime::parse_from_rfc3339(output[0]["at"].as_str().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn splits_arrays_and_runs_later_steps_on_each_item() {
+        let pipeline = pipeline(json!([
+            {"type": "split", "field": "items"},
+            {"type": "compute", "field": "total", "expr": "price * quantity"},
+        ]));
+
+        let output = pipeline
+            .apply(json!({
+                "order_id": 1,
+                "items": [{"price": 2, "quantity": 3}, {"price": 5, "quantity": 1}]
+            }))
+            .unwrap();
+
+        assert_eq!(
+            output,
+            vec![
+                json!({"order_id": 1, "price": 2, "quantity": 3, "total": 6}),
+                json!({"order_id": 1, "price": 5, "quantity": 1, "total": 5}),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_into_named_field() {
+        let pipeline = pipeline(json!([{"type": "split", "field": "tags", "into": "tag"}]));
+
+        let output = pipeline.apply(json!({"id": 1, "tags": ["a", "b"]})).unwrap();
+
+        assert_eq!(output, vec![json!({"id": 1, "tag": "a"}), json!({"id": 1, "tag": "b"})]);
+        assert!(pipeline.apply(json!({"id": 1})).is_err());
+        assert!(pipeline.apply(json!({"tags": "a"})).is_err());
+    }
+
+    #[test]
+    fn passes_messages_through_without_steps() {
+        let output = TransformPipeline::default().apply(json!([1, 2])).unwrap();
+
+        assert_eq!(output, vec![json!([1, 2])]);
+    }
+
+    #[test]
+    fn rejects_non_object_messages() {
+        let pipeline = pipeline(json!([{"type": "drop", "field": "a"}]));
+
+        assert!(pipeline.apply(json!("text")).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Context, Result};
+use jsonschema::{error::ValidationErrorKind, Validator};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::info;
+
+/// Field name used for problems with the message as a whole, such as a body
+/// that isn't JSON.
+pub const WHOLE_MESSAGE: &str = "$";
+
+/// One validation failure, with `field` as a JSON pointer into the body.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+    /// Keyword location in the schema, e.g. `/properties/quantity/minimum`,
+    /// or `$`. Unlike `field` it can't name array indices or properties the
+    /// producer made up, so it is safe as a metric label.
+    #[serde(skip)]
+    pub rule: String,
+}
+
+impl FieldError {
+    pub fn whole_message(message: String) -> Self {
+        Self {
+            field: WHOLE_MESSAGE.to_string(),
+            message,
+            rule: WHOLE_MESSAGE.to_string(),
+        }
+    }
+}
+
+/// JSON Schemas loaded from a directory, one file per version: `1.json`
+/// validates messages whose version header is `1`.
+pub struct SchemaRegistry {
+    schemas: HashMap<String, Validator>,
+}
+
+impl SchemaRegistry {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut schemas = HashMap::new();
+
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("failed to read schema directory {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(version) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            let source = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read schema {}", path.display()))?;
+            let schema: Value = serde_json::from_str(&source)
+                .with_context(|| format!("schema {} is not valid JSON", path.display()))?;
+            let validator = jsonschema::validator_for(&schema)
+                .map_err(|e| anyhow!("invalid schema {}: {}", path.display(), e))?;
+
+            schemas.insert(version.to_string(), validator);
+        }
+
+        if schemas.is_empty() {
+            return Err(anyhow!("no *.json schemas found in {}", dir.display()));
+        }
+
+        let mut versions: Vec<_> = schemas.keys().cloned().collect();
+        versions.sort();
+        info!(dir = %dir.display(), versions = ?versions, "Loaded message schemas");
+
+        Ok(Self { schemas })
+    }
+
+    pub fn knows(&self, version: &str) -> bool {
+        self.schemas.contains_key(version)
+    }
+
+    /// Validate a raw body against the schema for `version`. Returns every
+    /// violation found; an empty list means the message is valid.
+    pub fn validate(&self, version: &str, data: &[u8]) -> Vec<FieldError> {
+        let Some(validator) = self.schemas.get(version) else {
+            return vec![FieldError::whole_message(format!(
+                "unknown schema version '{}'",
+                version
+            ))];
+        };
+
+        let body: Value = match serde_json::from_slice(data) {
+            Ok(body) => body,
+            Err(e) => {
+                return vec![FieldError::whole_message(format!(
+                    "body is not valid JSON: {}",
+                    e
+                ))]
+            }
+        };
+
+        validator
+            .iter_errors(&body)
+            .map(|error| FieldError {
+                field: field_of(&error.instance_path.to_string(), &error.kind),
+                message: error.to_string(),
+                rule: error.schema_path.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Point at the offending property itself for errors that the validator
+/// reports on the enclosing object.
+fn field_of(instance_path: &str, kind: &ValidationErrorKind) -> String {
+    let child = match kind {
+        ValidationErrorKind::Required { property } => property.as_str().map(str::to_string),
+        ValidationErrorKind::AdditionalProperties { unexpected } if unexpected.len() == 1 => {
+            Some(unexpected[0].clone())
+        }
+        _ => None,
+    };
+
+    match child {
+        Some(property) => format!("{}/{}", instance_path, property),
+        None if instance_path.is_empty() => WHOLE_MESSAGE.to_string(),
+        None => instance_path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn registry() -> SchemaRegistry {
+        let dir = std::env::temp_dir().join(format!("schemas_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let schema = json!({
+            "type": "object",
+            "required": ["user_id", "customer"],
+            "additionalProperties": false,
+            "properties": {
+                "user_id": {"type": "string", "minLength": 1},
+                "quantity": {"type": "integer", "minimum": 1},
+                "customer": {
+                    "type": "object",
+                    "required": ["tier"],
+                    "properties": {"tier": {"type": "string"}}
+                }
+            }
+        });
+        std::fs::write(dir.join("1.json"), schema.to_string()).unwrap();
+        std::fs::write(dir.join("README.txt"), "not a schema").unwrap();
+
+        let registry = SchemaRegistry::load(&dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        registry
+    }
+
+    fn fields(errors: Vec<FieldError>) -> Vec<String> {
+        let mut fields: Vec<_> = errors.into_iter().map(|error| error.field).collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn accepts_valid_messages() {
+        let body = json!({"user_id": "u1", "quantity": 2, "customer": {"tier": "gold"}});
+
+        assert!(registry().validate("1", body.to_string().as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn points_at_missing_and_unexpected_properties() {
+        let body = json!({"quantity": 0, "customer": {}, "extra": true});
+
+        let errors = registry().validate("1", body.to_string().as_bytes());
+
+        assert_eq!(
+            fields(errors),
+            vec!["/customer/tier", "/extra", "/quantity", "/user_id"]
+        );
+    }
+
+    #[test]
+    fn labels_violations_by_schema_keyword() {
+        let body = json!({"user_id": "u1", "customer": {}, "x_1": true, "x_2": true});
+
+        let mut rules: Vec<_> = registry()
+            .validate("1", body.to_string().as_bytes())
+            .into_iter()
+            .map(|error| error.rule)
+            .collect();
+        rules.sort();
+
+        assert_eq!(
+            rules,
+            vec!["/additionalProperties", "/properties/customer/required"]
+        );
+    }
+
+    #[test]
+    fn reports_whole_message_problems() {
+        let registry = registry();
+
+        assert_eq!(fields(registry.validate("1", b"not json")), vec![WHOLE_MESSAGE]);
+        assert_eq!(fields(registry.validate("2", b"{}")), vec![WHOLE_MESSAGE]);
+        assert_eq!(fields(registry.validate("1", b"[]")), vec![WHOLE_MESSAGE]);
+    }
+}
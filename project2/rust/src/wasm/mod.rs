@@ -0,0 +1,330 @@
+//! `MessageHandler` backed by a WebAssembly module, so processing logic can
+//! be written in any language that compiles to wasm.
+//!
+//! ABI: the module must export
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`, returning a buffer of `len` bytes in `memory`
+//! - `dealloc(ptr: i32, len: i32)`, freeing a buffer from `alloc` or `handle`
+//! - `handle(body_ptr: i32, body_len: i32, headers_ptr: i32, headers_len: i32) -> i64`
+//!
+//! `handle` receives the raw message body and the AMQP headers as a JSON
+//! object. It returns `(ptr << 32) | len` of a JSON array in `memory`, each
+//! element shaped like `{"routing_key": "optional", "exchange": "optional",
+//! "headers": {...optional}, "body": {...}}`. The module gets no imports, so it has no access to the
+//! host beyond these buffers.
+//!
+//! Instances are reused across messages, so after each message the host
+//! passes the body, headers and output buffers back to `dealloc`. A module
+//! that never frees them would run into `max_memory_bytes` after enough
+//! messages.
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use lapin::message::Delivery;
+use std::sync::{Arc, Mutex};
+use tracing::{info, instrument, warn};
+use wasmtime::{Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use crate::config::WasmConfig;
+use crate::messaging::consumer::MessageHandler;
+use crate::messaging::headers::headers_to_json;
+use crate::messaging::RoutedMessage;
+
+struct InstanceState {
+    limits: StoreLimits,
+}
+
+struct PluginInstance {
+    store: Store<InstanceState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    dealloc: TypedFunc<(i32, i32), ()>,
+    handle: TypedFunc<(i32, i32, i32, i32), i64>,
+}
+
+struct Plugin {
+    engine: Engine,
+    module: Module,
+    linker: Linker<InstanceState>,
+    fuel: u64,
+    max_memory_bytes: usize,
+}
+
+#[derive(Clone)]
+pub struct WasmHandler {
+    plugin: Arc<Plugin>,
+    pool: Arc<Mutex<Vec<PluginInstance>>>,
+    exchange: String,
+}
+
+impl WasmHandler {
+    /// Compile the module and pre-instantiate one instance per worker.
+    pub fn load(
+        config: &WasmConfig,
+        workers: usize,
+    ) -> Result<Self> {
+        let mut engine_config = Config::new();
+        engine_config.consume_fuel(true);
+
+        let engine = Engine::new(&engine_config)?;
+        let module = Module::from_file(&engine, &config.path)
+            .with_context(|| format!("failed to load wasm module `{}`", config.path))?;
+
+        let plugin = Plugin {
+            linker: Linker::new(&engine),
+            engine,
+            module,
+            fuel: config.fuel,
+            max_memory_bytes: config.max_memory_bytes,
+        };
+
+        let pool = (0..workers)
+            .map(|_| plugin.instantiate())
+            .collect::<Result<Vec<_>>>()?;
+
+        info!(path = %config.path, instances = pool.len(), "Wasm handler loaded");
+
+        Ok(Self {
+            plugin: Arc::new(plugin),
+            pool: Arc::new(Mutex::new(pool)),
+            exchange: config.exchange.clone(),
+        })
+    }
+
+    async fn run(&self, body: Vec<u8>, headers: Vec<u8>) -> Result<Vec<RoutedMessage>> {
+        let instance = self.pool.lock().unwrap().pop();
+        let mut instance = match instance {
+            Some(instance) => instance,
+            None => self.plugin.instantiate()?,
+        };
+
+        let plugin = self.plugin.clone();
+        let (instance, result) = tokio::task::spawn_blocking(move || {
+            let result = plugin.call(&mut instance, &body, &headers);
+            (instance, result)
+        })
+        .await?;
+
+        // A trapped instance may have inconsistent memory, so only healthy
+        // ones go back to the pool
+        match &result {
+            Ok(_) => self.pool.lock().unwrap().push(instance),
+            Err(e) => warn!(error = %e, "Discarding wasm instance after failure"),
+        }
+
+        result
+    }
+}
+
+impl Plugin {
+    fn instantiate(&self) -> Result<PluginInstance> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.max_memory_bytes)
+            .instances(1)
+            .build();
+        let mut store = Store::new(&self.engine, InstanceState { limits });
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(self.fuel)?;
+
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("wasm module does not export `memory`"))?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let dealloc = instance.get_typed_func(&mut store, "dealloc")?;
+        let handle = instance.get_typed_func(&mut store, "handle")?;
+
+        Ok(PluginInstance {
+            store,
+            memory,
+            alloc,
+            dealloc,
+            handle,
+        })
+    }
+
+    fn call(&self, instance: &mut PluginInstance, body: &[u8], headers: &[u8]) -> Result<Vec<RoutedMessage>> {
+        // Every message gets a fresh fuel budget
+        instance.store.set_fuel(self.fuel)?;
+
+        let body_ptr = write_buffer(instance, body)?;
+        let headers_ptr = write_buffer(instance, headers)?;
+
+        let packed = instance.handle.call(
+            &mut instance.store,
+            (body_ptr, body.len() as i32, headers_ptr, headers.len() as i32),
+        )?;
+
+        let ptr = (packed as u64 >> 32) as usize;
+        let len = (packed as u64 & 0xffff_ffff) as usize;
+        let output = instance
+            .memory
+            .data(&instance.store)
+            .get(ptr..ptr + len)
+            .ok_or_else(|| anyhow!("wasm module returned an out-of-bounds output buffer"))?;
+        let outputs = serde_json::from_slice(output).context("wasm module returned invalid output");
+
+        for (ptr, len) in [
+            (body_ptr, body.len() as i32),
+            (headers_ptr, headers.len() as i32),
+            (ptr as i32, len as i32),
+        ] {
+            instance.dealloc.call(&mut instance.store, (ptr, len))?;
+        }
+
+        outputs
+    }
+}
+
+fn write_buffer(instance: &mut PluginInstance, data: &[u8]) -> Result<i32> {
+    let ptr = instance.alloc.call(&mut instance.store, data.len() as i32)?;
+    instance
+        .memory
+        .write(&mut instance.store, ptr as usize, data)
+        .context("wasm module returned an out-of-bounds buffer from alloc")?;
+    Ok(ptr)
+}
+
+#[async_trait]
+impl MessageHandler for WasmHandler {
+    #[instrument(skip(self, delivery))]
+    async fn handle(&self, delivery: &Delivery) -> Result<Vec<RoutedMessage>> {
+        let headers = serde_json::to_vec(&headers_to_json(&delivery.properties))?;
+        let mut outputs = self.run(delivery.data.clone(), headers).await?;
+
+        for output in &mut outputs {
+            output.exchange.get_or_insert_with(|| self.exchange.clone());
+        }
+
+        info!(outputs = outputs.len(), "Message handled by wasm plugin");
+
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Module with a bump allocator, a no-op `dealloc` and the given body
+    /// for `handle`. The output prefix `[{"routing_key":"out","body":` sits
+    /// at offset 0 and the suffix `}]` at offset 64.
+    fn module(handle: &str) -> String {
+        format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 1024))
+                (data (i32.const 0) "[{{\"routing_key\":\"out\",\"body\":")
+                (data (i32.const 64) "}}]")
+                (func $alloc (export "alloc") (param $len i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $next))
+                    (global.set $next (i32.add (global.get $next) (local.get $len)))
+                    (local.get $ptr))
+                (func (export "dealloc") (param i32 i32))
+                (func (export "handle")
+                    (param $body i32) (param $body_len i32)
+                    (param $headers i32) (param $headers_len i32)
+                    (result i64)
+                    (local $out i32) (local $len i32)
+                    {handle}))"#
+        )
+    }
+
+    /// Wrap the body as `[{"routing_key":"out","body":<body>}]`.
+    const ECHO: &str = r#"
+        (local.set $len (i32.add (local.get $body_len) (i32.const 31)))
+        (local.set $out (call $alloc (local.get $len)))
+        (memory.copy (local.get $out) (i32.const 0) (i32.const 29))
+        (memory.copy
+            (i32.add (local.get $out) (i32.const 29))
+            (local.get $body)
+            (local.get $body_len))
+        (memory.copy
+            (i32.add (i32.add (local.get $out) (i32.const 29)) (local.get $body_len))
+            (i32.const 64)
+            (i32.const 2))
+        (i64.or
+            (i64.shl (i64.extend_i32_u (local.get $out)) (i64.const 32))
+            (i64.extend_i32_u (local.get $len)))"#;
+
+    fn load(wat: &str, fuel: u64, max_memory_bytes: usize) -> Result<WasmHandler> {
+        let path = std::env::temp_dir().join(format!("wasm_{}.wat", uuid::Uuid::new_v4()));
+        std::fs::write(&path, wat).unwrap();
+        let config = WasmConfig {
+            path: path.to_string_lossy().into_owned(),
+            exchange: String::new(),
+            fuel,
+            max_memory_bytes,
+        };
+        let handler = WasmHandler::load(&config, 1);
+        std::fs::remove_file(&path).unwrap();
+        handler
+    }
+
+    async fn run(handler: &WasmHandler, body: serde_json::Value) -> Result<Vec<RoutedMessage>> {
+        handler
+            .run(serde_json::to_vec(&body).unwrap(), b"{}".to_vec())
+            .await
+    }
+
+    #[tokio::test]
+    async fn runs_the_module_and_reuses_its_instance() {
+        let handler = load(&module(ECHO), 1_000_000, 1 << 20).unwrap();
+
+        for n in 0..3 {
+            let outputs = run(&handler, json!({"n": n})).await.unwrap();
+            assert_eq!(outputs.len(), 1);
+            assert_eq!(outputs[0].routing_key.as_deref(), Some("out"));
+            assert_eq!(outputs[0].body, json!({"n": n}));
+        }
+        assert_eq!(handler.pool.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_a_module_that_runs_out_of_fuel() {
+        let handler = load(&module("(loop $spin (br $spin)) (unreachable)"), 10_000, 1 << 20).unwrap();
+
+        let error = run(&handler, json!({})).await.unwrap_err();
+        assert!(
+            matches!(error.downcast_ref::<wasmtime::Trap>(), Some(wasmtime::Trap::OutOfFuel)),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn discards_an_instance_that_traps() {
+        let handler = load(&module("(unreachable)"), 1_000_000, 1 << 20).unwrap();
+        assert_eq!(handler.pool.lock().unwrap().len(), 1);
+
+        assert!(run(&handler, json!({})).await.is_err());
+        assert!(handler.pool.lock().unwrap().is_empty());
+
+        // The next message gets a fresh instance, which is discarded too
+        assert!(run(&handler, json!({})).await.is_err());
+        assert!(handler.pool.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_an_out_of_bounds_output_buffer() {
+        // One 64KiB page; ptr 65500 with len 100 runs past its end
+        let past_end = (65_500i64 << 32) | 100;
+        let handler = load(&module(&format!("(i64.const {past_end})")), 1_000_000, 1 << 20).unwrap();
+        let error = run(&handler, json!({})).await.unwrap_err();
+        assert!(error.to_string().contains("out-of-bounds output buffer"), "{error}");
+
+        // A length near u32::MAX must not wrap around
+        let huge = (16i64 << 32) | 0xffff_fff0;
+        let handler = load(&module(&format!("(i64.const {huge})")), 1_000_000, 1 << 20).unwrap();
+        let error = run(&handler, json!({})).await.unwrap_err();
+        assert!(error.to_string().contains("out-of-bounds output buffer"), "{error}");
+    }
+
+    #[test]
+    fn refuses_a_module_over_the_memory_limit() {
+        let wat = module(ECHO).replacen("(memory (export \"memory\") 1)", "(memory (export \"memory\") 4)", 1);
+        assert!(load(&wat, 1_000_000, 2 * 65_536).is_err());
+    }
+}
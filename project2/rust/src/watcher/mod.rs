@@ -0,0 +1,116 @@
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Poll a file's modification time and invoke `on_change` whenever it moves.
+/// Polling stops once `on_change` returns `ControlFlow::Break`, typically
+/// because whatever it reloads has been dropped.
+///
+/// Polling keeps this independent of platform file notification APIs, which
+/// behave inconsistently on bind-mounted config volumes.
+pub fn watch_file<F>(path: PathBuf, interval: Duration, on_change: F) -> JoinHandle<()>
+where
+    F: Fn() -> ControlFlow<()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut last_modified = modified(&path, true);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let current = modified(&path, last_modified.is_some());
+            if current.is_some() && last_modified.is_none() {
+                info!(path = %path.display(), "Watched file is back");
+            }
+            if current.is_some() && current != last_modified {
+                last_modified = current;
+                if on_change().is_break() {
+                    break;
+                }
+            } else if current.is_none() {
+                last_modified = None;
+            }
+        }
+    })
+}
+
+/// The file's modification time. A failure is only logged when `warn_missing`
+/// is set, so a file that stays missing is reported once rather than on every
+/// poll.
+fn modified(path: &Path, warn_missing: bool) -> Option<SystemTime> {
+    match std::fs::metadata(path).and_then(|meta| meta.modified()) {
+        Ok(time) => Some(time),
+        Err(e) => {
+            if warn_missing {
+                warn!(path = %path.display(), error = %e, "Failed to stat watched file");
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn stops_polling_when_callback_breaks() {
+        let path = std::env::temp_dir().join(format!("watch_file_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "a").unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let handle = watch_file(path.clone(), Duration::from_millis(10), move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            ControlFlow::Break(())
+        });
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(2), handle)
+            .await
+            .expect("watcher kept polling")
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_once_a_missing_file_comes_back() {
+        let path = std::env::temp_dir().join(format!("watch_file_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "a").unwrap();
+        let modified_at = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let handle = watch_file(path.clone(), Duration::from_millis(10), move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            ControlFlow::Continue(())
+        });
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        std::fs::remove_file(&path).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // Same modification time as before, but it is a new file
+        std::fs::write(&path, "b").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified_at).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        handle.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+}